@@ -0,0 +1,194 @@
+//! Questions a SQRL server can ask the user during a login
+
+use crate::{
+    error::{SqrlError, SqrlErrorKind},
+    Result,
+};
+use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine};
+use std::collections::VecDeque;
+
+/// A message (with up to two buttons) that a server wants shown to the user
+#[derive(Clone, Debug, PartialEq)]
+pub struct Ask {
+    /// The message to show to the user
+    pub message: String,
+    /// The buttons the user can choose between
+    pub buttons: Vec<AskButton>,
+}
+
+/// A button shown with an [`Ask`] message
+#[derive(Clone, Debug, PartialEq)]
+pub struct AskButton {
+    /// The text of the button
+    pub label: String,
+    /// A url the server wants opened if the button is chosen
+    pub url: Option<String>,
+}
+
+/// How the user answered an [`Ask`]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AskAnswer {
+    /// The user chose a button (1 for the first button, 2 for the second)
+    Button(u8),
+    /// The user dismissed a message that did not have any buttons
+    Dismissed,
+    /// The user does not want to continue, failing the login with
+    /// [`SqrlErrorKind::Cancelled`]
+    Cancel,
+}
+
+/// Shows the server's questions to the user and returns their answers
+///
+/// This is implemented for any `FnMut(&Ask) -> AskAnswer` closure.
+pub trait AskHandler {
+    /// Called when the server asks the user a question
+    fn on_ask(&mut self, ask: &Ask) -> AskAnswer;
+}
+
+impl<F> AskHandler for F
+where
+    F: FnMut(&Ask) -> AskAnswer,
+{
+    fn on_ask(&mut self, ask: &Ask) -> AskAnswer {
+        self(ask)
+    }
+}
+
+/// An [`AskHandler`] that gives a fixed list of answers, for running logins
+/// without a user (such as in tests)
+///
+/// Once the answers run out, any further questions are cancelled.
+#[derive(Clone, Debug, Default)]
+pub struct ScriptedAskHandler {
+    answers: VecDeque<AskAnswer>,
+    asked: Vec<Ask>,
+}
+
+impl ScriptedAskHandler {
+    /// Create a handler that gives the answers in order
+    pub fn new(answers: Vec<AskAnswer>) -> Self {
+        ScriptedAskHandler {
+            answers: answers.into(),
+            asked: Vec::new(),
+        }
+    }
+
+    /// The questions the handler has been asked so far
+    pub fn asked(&self) -> &[Ask] {
+        &self.asked
+    }
+}
+
+impl AskHandler for ScriptedAskHandler {
+    fn on_ask(&mut self, ask: &Ask) -> AskAnswer {
+        self.asked.push(ask.clone());
+        self.answers.pop_front().unwrap_or(AskAnswer::Cancel)
+    }
+}
+
+impl Ask {
+    /// Parse the value of a server's ask= parameter
+    ///
+    /// The message and each button are base64url encoded and separated by a
+    /// '~', and a button's label can be followed by a ';' and a url.
+    pub fn parse(value: &str) -> Result<Self> {
+        let mut parts = value.split('~');
+        let message = decode(parts.next().unwrap_or_default())?;
+
+        let mut buttons = Vec::new();
+        for part in parts {
+            let button = decode(part)?;
+            buttons.push(match button.split_once(';') {
+                Some((label, url)) => AskButton {
+                    label: label.to_owned(),
+                    url: Some(url.to_owned()),
+                },
+                None => AskButton {
+                    label: button,
+                    url: None,
+                },
+            });
+        }
+
+        if buttons.len() > 2 {
+            return Err(SqrlError::new(
+                "A server can only ask with up to two buttons".to_owned(),
+            ));
+        }
+
+        Ok(Ask { message, buttons })
+    }
+
+    // Turn the user's answer into the btn= value for the next request
+    pub(crate) fn button_value(&self, answer: AskAnswer) -> Result<Option<u8>> {
+        match answer {
+            AskAnswer::Button(button)
+                if button >= 1 && usize::from(button) <= self.buttons.len() =>
+            {
+                Ok(Some(button))
+            }
+            AskAnswer::Button(button) => Err(SqrlError::new(format!(
+                "There is no button {} to choose",
+                button
+            ))),
+            AskAnswer::Dismissed => Ok(None),
+            AskAnswer::Cancel => Err(SqrlError::with_kind(
+                SqrlErrorKind::Cancelled,
+                "The login was cancelled".to_owned(),
+            )),
+        }
+    }
+}
+
+fn decode(value: &str) -> Result<String> {
+    let decoded = BASE64_URL_SAFE_NO_PAD.decode(value.trim_end_matches('='))?;
+    Ok(String::from_utf8(decoded)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(value: &str) -> String {
+        BASE64_URL_SAFE_NO_PAD.encode(value)
+    }
+
+    #[test]
+    fn parse_ask_with_buttons() {
+        let value = format!(
+            "{}~{}~{}",
+            encode("Link this account?"),
+            encode("Yes"),
+            encode("Tell me more;https://example.com/help")
+        );
+        let ask = Ask::parse(&value).unwrap();
+        assert_eq!(ask.message, "Link this account?");
+        assert_eq!(
+            ask.buttons,
+            vec![
+                AskButton {
+                    label: "Yes".to_owned(),
+                    url: None,
+                },
+                AskButton {
+                    label: "Tell me more".to_owned(),
+                    url: Some("https://example.com/help".to_owned()),
+                },
+            ]
+        );
+
+        assert_eq!(ask.button_value(AskAnswer::Button(2)).unwrap(), Some(2));
+        assert!(ask.button_value(AskAnswer::Button(3)).is_err());
+        assert_eq!(
+            ask.button_value(AskAnswer::Cancel).unwrap_err().kind(),
+            &SqrlErrorKind::Cancelled
+        );
+    }
+
+    #[test]
+    fn parse_ask_rejects_bad_data() {
+        assert!(Ask::parse("not base64!").is_err());
+        let value = [encode("?"), encode("1"), encode("2"), encode("3")].join("~");
+        assert!(Ask::parse(&value).is_err());
+    }
+}
@@ -0,0 +1,222 @@
+//! Async versions of the SqrlClient operations that run EnScrypt
+//!
+//! EnScrypt is CPU-bound for several seconds by design, so each of these runs
+//! the work on tokio's blocking thread pool instead of the async executor.
+
+use crate::{secret::Secret, Result, SqrlClient, UnlockedIdentity};
+use sqrl_protocol::client_request::ClientRequest;
+use tokio::task;
+
+impl SqrlClient {
+    /// Create a new SQRL client protecting the data with the password
+    pub async fn new_async(password: &str) -> Result<(Self, Secret<String>)> {
+        let password = Secret::new(password.to_owned());
+        run_blocking(move || SqrlClient::new(&password)).await
+    }
+
+    /// Verify that the password is the correct password
+    pub async fn verify_password_async(&self, password: &str) -> Result<()> {
+        let mut client = self.clone();
+        let password = Secret::new(password.to_owned());
+        run_blocking(move || client.verify_password(&password)).await
+    }
+
+    /// Change the password for the encrypted client data
+    pub async fn change_password_async(
+        &mut self,
+        current_password: &str,
+        new_password: &str,
+    ) -> Result<()> {
+        let mut client = self.clone();
+        let current_password = Secret::new(current_password.to_owned());
+        let new_password = Secret::new(new_password.to_owned());
+        *self = run_blocking(move || {
+            client.change_password(&current_password, &new_password)?;
+            Ok(client)
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    /// Generate a new identity, storing the previous identity in the list of previous identities
+    pub async fn rekey_identity_async(
+        &mut self,
+        password: &str,
+        rescue_code: &str,
+    ) -> Result<Secret<String>> {
+        let mut client = self.clone();
+        let password = Secret::new(password.to_owned());
+        let rescue_code = Secret::new(rescue_code.to_owned());
+        let (client, new_rescue_code) = run_blocking(move || {
+            let new_rescue_code = client.rekey_identity(&password, &rescue_code)?;
+            Ok((client, new_rescue_code))
+        })
+        .await?;
+
+        *self = client;
+        Ok(new_rescue_code)
+    }
+
+    /// Recreate the SQRL data from a rescue code
+    pub async fn recreate_from_rescue_code_async(
+        &self,
+        rescue_code: &str,
+        new_password: &str,
+    ) -> Result<Self> {
+        let client = self.clone();
+        let rescue_code = Secret::new(rescue_code.to_owned());
+        let new_password = Secret::new(new_password.to_owned());
+        run_blocking(move || client.recreate_from_rescue_code(&rescue_code, &new_password)).await
+    }
+
+    /// Decrypt the identity with the password, returning a session that can be
+    /// used for multiple operations without running EnScrypt again
+    pub async fn unlock_async(&self, password: &str) -> Result<UnlockedIdentity> {
+        let client = self.clone();
+        let password = Secret::new(password.to_owned());
+        run_blocking(move || client.unlock(&password)).await
+    }
+
+    /// Sign a client request with the key generated by the url and alternate identity
+    ///
+    /// Only decrypting the identity is slow, so the signing itself is done on
+    /// the calling task.
+    pub async fn sign_request_async(
+        &self,
+        password: &str,
+        url: &str,
+        alternate_identity: Option<&str>,
+        request: &mut ClientRequest,
+        previous_key_index: Option<usize>,
+    ) -> Result<()> {
+        self.unlock_async(password).await?.sign_request(
+            url,
+            alternate_identity,
+            request,
+            previous_key_index,
+        )
+    }
+}
+
+async fn run_blocking<T, F>(f: F) -> Result<T>
+where
+    F: FnOnce() -> Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    task::spawn_blocking(f).await?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::test_client;
+    use ed25519_dalek::Signature;
+    use sqrl_protocol::client_request::{ClientCommand, ClientParameters, ServerData};
+
+    const TEST_FILE_PATH: &str = "test_resources/Spec-Vectors-Identity.sqrl";
+    const TEST_FILE_PASSWORD: &str = "Zingo-Bingo-Slingo-Dingo";
+    const TEST_URL: &str = "sqrl://sqrl.grc.com/cli.sqrl?nut=fXkb4MBToCm7";
+
+    #[tokio::test]
+    async fn async_matches_sync() {
+        let client = SqrlClient::from_file(TEST_FILE_PATH).unwrap();
+        client
+            .verify_password_async(TEST_FILE_PASSWORD)
+            .await
+            .unwrap();
+        assert!(client.verify_password_async("wrong").await.is_err());
+
+        let unlocked = client.unlock_async(TEST_FILE_PASSWORD).await.unwrap();
+        assert_eq!(
+            unlocked.get_public_identity(TEST_URL, None).unwrap(),
+            client
+                .get_public_identity(TEST_FILE_PASSWORD, TEST_URL, None)
+                .unwrap()
+        );
+    }
+
+    fn test_request(client: &SqrlClient) -> ClientRequest {
+        let params = ClientParameters::new(
+            ClientCommand::Query,
+            client
+                .get_public_identity("password", TEST_URL, None)
+                .unwrap(),
+        );
+        let server = ServerData::Url {
+            url: crate::parse_url(TEST_URL).unwrap(),
+        };
+        ClientRequest::new(params, server, Signature::from_bytes(&[0; 64]))
+    }
+
+    #[tokio::test]
+    async fn new_async_creates_identity() {
+        let (mut client, rescue_code) = SqrlClient::new_async("password").await.unwrap();
+        client.verify_password("password").unwrap();
+        assert!(client.unlock_with_rescue_code(&rescue_code).is_ok());
+    }
+
+    #[tokio::test]
+    async fn change_password_async_updates_client() {
+        let (mut client, _) = test_client();
+        client
+            .change_password_async("password", "new_password")
+            .await
+            .unwrap();
+        client.verify_password("new_password").unwrap();
+        assert!(client.verify_password("password").is_err());
+    }
+
+    #[tokio::test]
+    async fn rekey_identity_async_updates_client() {
+        let (mut client, rescue_code) = test_client();
+        let original = client
+            .get_public_identity("password", TEST_URL, None)
+            .unwrap();
+        let new_rescue_code = client
+            .rekey_identity_async("password", &rescue_code)
+            .await
+            .unwrap();
+
+        let unlocked = client.unlock("password").unwrap();
+        assert_eq!(unlocked.previous_identity_count(), 1);
+        assert_ne!(
+            unlocked.get_public_identity(TEST_URL, None).unwrap(),
+            original
+        );
+        assert!(client.unlock_with_rescue_code(&new_rescue_code).is_ok());
+    }
+
+    #[tokio::test]
+    async fn recreate_from_rescue_code_async_keeps_identity() {
+        let (client, rescue_code) = test_client();
+        let recreated = client
+            .recreate_from_rescue_code_async(&rescue_code, "new_password")
+            .await
+            .unwrap();
+        assert_eq!(
+            recreated
+                .get_public_identity("new_password", TEST_URL, None)
+                .unwrap(),
+            client
+                .get_public_identity("password", TEST_URL, None)
+                .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn sign_request_async_matches_sync() {
+        let (client, _) = test_client();
+        let mut request = test_request(&client);
+        client
+            .sign_request_async("password", TEST_URL, None, &mut request, None)
+            .await
+            .unwrap();
+
+        let mut expected = test_request(&client);
+        client
+            .sign_request("password", TEST_URL, None, &mut expected, None)
+            .unwrap();
+        assert_eq!(request.identity_signature, expected.identity_signature);
+    }
+}
@@ -0,0 +1,405 @@
+//! Manage a SQRL identity from the command line
+
+use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine};
+use clap::{Parser, Subcommand, ValueEnum};
+use ed25519_dalek::Signature;
+use sqrl_client::{
+    common::encode_request, error::SqrlError, Result, S4Inspector, ScryptSummary, Secret,
+    SqrlClient,
+};
+use sqrl_protocol::{
+    client_request::{ClientCommand, ClientParameters, ClientRequest, ServerData},
+    SqrlUrl,
+};
+use std::{
+    ffi::OsString,
+    fs::{self, OpenOptions},
+    io::{self, IsTerminal, Write},
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
+
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+
+/// Manage a SQRL identity
+#[derive(Debug, Parser)]
+#[command(name = "sqrl", version)]
+struct Cli {
+    /// The identity file to use
+    #[arg(short, long, value_name = "FILE", default_value = "identity.sqrl")]
+    identity: PathBuf,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Create a new identity, printing its rescue code
+    Create {
+        /// Replace the identity file if it already exists
+        #[arg(long)]
+        force: bool,
+    },
+    /// Show what is stored in the identity file, without needing the password
+    Info,
+    /// Check the password for the identity
+    Verify,
+    /// Change the password protecting the identity
+    ChangePassword,
+    /// Replace the identity with a new one, keeping the old one as a previous identity
+    Rekey,
+    /// Write the identity out in another format
+    Export {
+        /// The format to write
+        #[arg(long, value_enum, default_value_t = Format::Binary)]
+        format: Format,
+        /// Where to write the identity (stdout if not given)
+        #[arg(short, long, value_name = "FILE")]
+        output: Option<PathBuf>,
+    },
+    /// Read an identity from another file, saving it as the identity file
+    Import {
+        /// The file to read the identity from
+        input: PathBuf,
+        /// The format of the file
+        #[arg(long, value_enum, default_value_t = Format::Binary)]
+        format: Format,
+        /// Replace the identity file if it already exists
+        #[arg(long)]
+        force: bool,
+    },
+    /// Print the identity key (idk) used for a site
+    ShowPublicKey {
+        /// The sqrl:// url of the site
+        url: String,
+        /// The alternate identity to use for the site
+        #[arg(long)]
+        alternate_identity: Option<String>,
+    },
+    /// Print the secret index (ins) for a site's sin= value
+    Sin {
+        /// The sqrl:// url of the site
+        url: String,
+        /// The sin= value sent by the site
+        index: String,
+        /// The alternate identity to use for the site
+        #[arg(long)]
+        alternate_identity: Option<String>,
+    },
+    /// Print a signed query for a site, as it would be POSTed to the server
+    Sign {
+        /// The sqrl:// url of the site
+        url: String,
+        /// The alternate identity to use for the site
+        #[arg(long)]
+        alternate_identity: Option<String>,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum Format {
+    /// The S4 binary format (a .sqrl file)
+    Binary,
+    /// The S4 binary format, base64 encoded after a SQRLDATA header
+    Base64,
+    /// The textual identity, which only holds the rescue code protected data
+    Text,
+}
+
+fn main() -> ExitCode {
+    match run(Cli::parse()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(cli: Cli) -> Result<()> {
+    let identity = cli.identity.as_path();
+    match cli.command {
+        Command::Create { force } => {
+            check_can_write(identity, force)?;
+            let password = read_new_password()?;
+            let (client, rescue_code) = SqrlClient::new(&password)?;
+            write_identity(&client, identity)?;
+            println!(
+                "Rescue code (write this down and keep it safe): {}",
+                rescue_code.as_str()
+            );
+        }
+        Command::Info => {
+            let inspector = S4Inspector::from_file(identity)?;
+            for block in inspector.blocks() {
+                let name = match block.block_type {
+                    1 => "password",
+                    2 => "rescue code",
+                    3 => "previous identities",
+                    _ => "unknown",
+                };
+                println!(
+                    "Block type {} ({}): {} bytes",
+                    block.block_type, name, block.length
+                );
+            }
+            match inspector.password_scrypt() {
+                Some(scrypt) => print_scrypt("Password", scrypt),
+                None => println!("No password block"),
+            }
+            match inspector.rescue_code_scrypt() {
+                Some(scrypt) => print_scrypt("Rescue code", scrypt),
+                None => println!("No rescue code block"),
+            }
+            if let Some(option_flags) = inspector.option_flags() {
+                println!("Options: {:?}", option_flags);
+            }
+            if let Some(hint_length) = inspector.hint_length() {
+                println!("Hint length: {}", hint_length);
+            }
+            if let Some(pw_verify_sec) = inspector.pw_verify_sec() {
+                println!("Password verify seconds: {}", pw_verify_sec);
+            }
+            if let Some(idle_timeout_min) = inspector.idle_timeout_min() {
+                println!("Idle timeout (minutes): {}", idle_timeout_min);
+            }
+            println!(
+                "Previous identities: {}",
+                inspector.previous_identity_count()
+            );
+            if let Some(offset) = inspector.corrupt_offset() {
+                return Err(SqrlError::new(format!(
+                    "The data is corrupt at offset {}",
+                    offset
+                )));
+            }
+        }
+        Command::Verify => {
+            let mut client = SqrlClient::from_file(identity)?;
+            client.verify_password(&read_secret("Password: ")?)?;
+            println!("The password is correct");
+        }
+        Command::ChangePassword => {
+            let mut client = SqrlClient::from_file(identity)?;
+            let current_password = read_secret("Current password: ")?;
+            let new_password = read_new_password()?;
+            client.change_password(&current_password, &new_password)?;
+            write_identity(&client, identity)?;
+        }
+        Command::Rekey => {
+            let mut client = SqrlClient::from_file(identity)?;
+            let password = read_secret("Password: ")?;
+            let rescue_code = read_secret("Rescue code: ")?;
+            let new_rescue_code = client.rekey_identity(&password, &rescue_code)?;
+            write_identity(&client, identity)?;
+            println!(
+                "New rescue code (write this down and keep it safe): {}",
+                new_rescue_code.as_str()
+            );
+        }
+        Command::Export { format, output } => {
+            let client = SqrlClient::from_file(identity)?;
+            let data = match format {
+                Format::Binary => {
+                    let mut data = Vec::new();
+                    client.write_to(&mut data)?;
+                    data
+                }
+                Format::Base64 => format!("{}\n", client.to_base64()?).into_bytes(),
+                Format::Text => format!("{}\n", client.to_textual_identity_format()?).into_bytes(),
+            };
+            match output {
+                // The export holds the identity, so keep it as private as the identity file
+                Some(output) => write_private_file(&output, &data)?,
+                None => io::stdout().write_all(&data)?,
+            }
+        }
+        Command::Import {
+            input,
+            format,
+            force,
+        } => {
+            check_can_write(identity, force)?;
+            let client = match format {
+                Format::Binary => SqrlClient::from_file(input)?,
+                Format::Base64 => SqrlClient::from_base64(fs::read_to_string(input)?.trim())?,
+                Format::Text => {
+                    // Only the rescue code protected data is in the textual identity
+                    let text = fs::read_to_string(input)?;
+                    let rescue_code = read_secret("Rescue code: ")?;
+                    let new_password = read_new_password()?;
+                    SqrlClient::from_textual_identity_format(
+                        text.trim(),
+                        &rescue_code,
+                        &new_password,
+                    )?
+                }
+            };
+            write_identity(&client, identity)?;
+        }
+        Command::ShowPublicKey {
+            url,
+            alternate_identity,
+        } => {
+            let client = SqrlClient::from_file(identity)?;
+            let password = read_secret("Password: ")?;
+            let key = client.get_public_identity(&password, &url, alternate_identity.as_deref())?;
+            println!("{}", BASE64_URL_SAFE_NO_PAD.encode(key.as_bytes()));
+        }
+        Command::Sin {
+            url,
+            index,
+            alternate_identity,
+        } => {
+            let client = SqrlClient::from_file(identity)?;
+            let password = read_secret("Password: ")?;
+            let ins = client.get_secret_index_key(
+                &password,
+                &url,
+                alternate_identity.as_deref(),
+                &index,
+            )?;
+            println!("{}", ins);
+        }
+        Command::Sign {
+            url,
+            alternate_identity,
+        } => {
+            let client = SqrlClient::from_file(identity)?;
+            let password = read_secret("Password: ")?;
+            let unlocked = client.unlock(&password)?;
+            let alternate_identity = alternate_identity.as_deref();
+
+            let params = ClientParameters::new(
+                ClientCommand::Query,
+                unlocked.get_public_identity(&url, alternate_identity)?,
+            );
+            let server = ServerData::Url {
+                url: SqrlUrl::parse(&url).map_err(|e| SqrlError::new(e.to_string()))?,
+            };
+            // The signature is filled in by sign_request
+            let mut request = ClientRequest::new(params, server, Signature::from_bytes(&[0; 64]));
+            unlocked.sign_request(&url, alternate_identity, &mut request, None)?;
+            println!("{}", encode_request(&request));
+        }
+    }
+
+    Ok(())
+}
+
+fn print_scrypt(block: &str, scrypt: &ScryptSummary) {
+    let salt: String = scrypt
+        .salt
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    println!(
+        "{} EnScrypt: salt {}, log N {}, {} iterations",
+        block, salt, scrypt.log_n_factor, scrypt.iteration_count
+    );
+}
+
+fn check_can_write(identity: &Path, force: bool) -> Result<()> {
+    if identity.exists() && !force {
+        return Err(SqrlError::new(format!(
+            "{} already exists (use --force to replace it)",
+            identity.display()
+        )));
+    }
+
+    Ok(())
+}
+
+fn write_identity(client: &SqrlClient, identity: &Path) -> Result<()> {
+    let mut data = Vec::new();
+    client.write_to(&mut data)?;
+    write_private_file(identity, &data)
+}
+
+// Write the data to a new file next to the path, then rename that over the top, so
+// a failed write can't leave the file half written
+fn write_private_file(path: &Path, data: &[u8]) -> Result<()> {
+    let file_name = path.file_name().ok_or(SqrlError::new(format!(
+        "{} is not a file name",
+        path.display()
+    )))?;
+    let mut temp_name = OsString::from(".");
+    temp_name.push(file_name);
+    temp_name.push(".tmp");
+    let temp_path = path.with_file_name(temp_name);
+
+    // Clear out anything left behind by an earlier run that didn't finish
+    let _ = fs::remove_file(&temp_path);
+    let result = write_new_file(&temp_path, data).and_then(|()| Ok(fs::rename(&temp_path, path)?));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result
+}
+
+// Only the owner can read the file, and nothing already at the path is followed
+fn write_new_file(path: &Path, data: &[u8]) -> Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+
+    let mut file = options.open(path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+// Prompt on the terminal if there is one, otherwise read the next line of stdin
+fn read_secret(prompt: &str) -> Result<Secret<String>> {
+    if io::stdin().is_terminal() {
+        return Ok(Secret::new(rpassword::prompt_password(prompt)?));
+    }
+
+    // Leave room for a long password, so the line isn't copied as it grows
+    let mut line = Secret::new(String::with_capacity(256));
+    if io::stdin().read_line(&mut line)? == 0 {
+        return Err(SqrlError::new(format!(
+            "Expected input for \"{}\" on stdin",
+            prompt.trim_end_matches([':', ' '])
+        )));
+    }
+    let length = line.trim_end_matches(['\r', '\n']).len();
+    line.truncate(length);
+    Ok(line)
+}
+
+// Typing a new password twice catches typos, which aren't a problem when it's piped in
+fn read_new_password() -> Result<Secret<String>> {
+    let password = read_secret("New password: ")?;
+    if io::stdin().is_terminal() && password != read_secret("Confirm new password: ")? {
+        return Err(SqrlError::new("The passwords do not match".to_owned()));
+    }
+
+    Ok(password)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn cli_is_valid() {
+        Cli::command().debug_assert();
+
+        let cli =
+            Cli::try_parse_from(["sqrl", "-i", "me.sqrl", "export", "--format", "text"]).unwrap();
+        assert_eq!(cli.identity, PathBuf::from("me.sqrl"));
+        assert!(matches!(
+            cli.command,
+            Command::Export {
+                format: Format::Text,
+                output: None
+            }
+        ));
+        assert!(Cli::try_parse_from(["sqrl", "sin", "sqrl://example.com/"]).is_err());
+    }
+}
@@ -1,9 +1,10 @@
 //! Common code used by both SQRL clients and servers
 
-use crate::{error::SqrlError, Result};
-use ed25519_dalek::VerifyingKey;
+use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine};
+use ed25519_dalek::{SigningKey, VerifyingKey};
 use sha2::{Digest, Sha256};
-use x25519_dalek::PublicKey;
+use sqrl_protocol::client_request::{ClientParameters, ClientRequest};
+use x25519_dalek::{PublicKey, StaticSecret};
 
 /// THe keys needed to unlock a SQRL identity
 pub struct IdentityUnlockKeys {
@@ -23,17 +24,96 @@ impl IdentityUnlockKeys {
     }
 }
 
-pub(crate) fn slice_to_u8_32(slice: &[u8]) -> Result<[u8; 32]> {
-    let mut result = [0; 32];
-    if slice.len() != 32 {
-        return Err(SqrlError::new(format!(
-            "Error converting vec<u8> to [u8; 32]: Expected 32 bytes, but found {}",
-            slice.len()
-        )));
+/// Encode the client parameters of a request (the client= value) as base64url
+///
+/// The values are always written in the same order, so the text that is
+/// signed is the text that gets sent. `ClientParameters::to_base64` can put
+/// them in a different order each time it is called.
+pub fn encode_client_parameters(params: &ClientParameters) -> String {
+    let mut values = vec![
+        ("ver", params.protocol_version.to_string()),
+        ("cmd", params.command.to_string()),
+        (
+            "idk",
+            BASE64_URL_SAFE_NO_PAD.encode(params.identity_key.as_bytes()),
+        ),
+    ];
+    if let Some(previous_identity_key) = &params.previous_identity_key {
+        values.push((
+            "pidk",
+            BASE64_URL_SAFE_NO_PAD.encode(previous_identity_key.as_bytes()),
+        ));
     }
+    if let Some(options) = &params.options {
+        let options: Vec<String> = options.iter().map(|option| option.to_string()).collect();
+        values.push(("opt", options.join("~")));
+    }
+    if let Some(button) = params.button {
+        values.push(("btn", button.to_string()));
+    }
+    if let Some(index_secret) = &params.index_secret {
+        values.push(("ins", index_secret.clone()));
+    }
+    if let Some(previous_index_secret) = &params.previous_index_secret {
+        values.push(("pins", previous_index_secret.clone()));
+    }
+    if let Some(server_unlock_key) = &params.server_unlock_key {
+        values.push(("suk", server_unlock_key.clone()));
+    }
+    if let Some(verify_unlock_key) = &params.verify_unlock_key {
+        values.push(("vuk", verify_unlock_key.clone()));
+    }
+
+    let text: String = values
+        .iter()
+        .map(|(key, value)| format!("{}={}\r\n", key, value))
+        .collect();
+    BASE64_URL_SAFE_NO_PAD.encode(text)
+}
+
+/// The text that the signatures of a request (ids, pids and urs) are made over
+pub fn signed_string(request: &ClientRequest) -> String {
+    format!(
+        "{}{}",
+        encode_client_parameters(&request.client_params),
+        request.server_data.to_base64()
+    )
+}
+
+/// Encode a signed request as the body to POST to the server
+///
+/// Use this rather than `ClientRequest::to_query_string`, which encodes the
+/// client parameters differently from what was signed (see
+/// [`encode_client_parameters`]) and doesn't base64url encode a server url.
+pub fn encode_request(request: &ClientRequest) -> String {
+    let mut body = format!(
+        "client={}&server={}&ids={}",
+        encode_client_parameters(&request.client_params),
+        request.server_data.to_base64(),
+        BASE64_URL_SAFE_NO_PAD.encode(request.identity_signature.to_bytes())
+    );
+    if let Some(previous_identity_signature) = &request.previous_identity_signature {
+        body += &format!(
+            "&pids={}",
+            BASE64_URL_SAFE_NO_PAD.encode(previous_identity_signature.to_bytes())
+        );
+    }
+    if let Some(unlock_request_signature) = &request.unlock_request_signature {
+        body += &format!("&urs={}", unlock_request_signature);
+    }
+
+    body
+}
 
-    result[..32].copy_from_slice(&slice[..32]);
-    Ok(result)
+// The key that signs unlock requests (urs) comes from a Diffie-Hellman key
+// exchange between the identity unlock key and the server unlock key
+pub(crate) fn unlock_request_signing_key(
+    identity_unlock_key: &[u8; 32],
+    server_unlock_key: [u8; 32],
+) -> SigningKey {
+    let secret_key = StaticSecret::from(*identity_unlock_key);
+    let shared_secret = secret_key.diffie_hellman(&PublicKey::from(server_unlock_key));
+    SigningKey::from_bytes(shared_secret.as_bytes())
 }
 
 pub(crate) fn en_hash(input: &[u8]) -> [u8; 32] {
@@ -0,0 +1,460 @@
+//! A client provided session (CPS) responder, which logs the browser in
+//! through a web server on localhost
+
+use crate::{
+    ask::{Ask, AskAnswer},
+    login::{LoginOutcome, LoginSession},
+    security::{SecurityWarning, WarningAction},
+    transport::SqrlTransport,
+    Result, UnlockedIdentity,
+};
+use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine};
+use std::{
+    cell::RefCell,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{Ipv4Addr, Ipv6Addr, TcpListener, TcpStream},
+    sync::mpsc,
+    thread,
+    time::Duration,
+};
+use url::Url;
+
+/// The port a CPS responder listens on
+pub const CPS_PORT: u16 = 25519;
+
+// How long a browser has to send each part of its request
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How long to wait after a connection couldn't be accepted, so a problem that
+// lasts a while (such as running out of file descriptors) doesn't spin
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+// The most that is read of the request line, and of the headers after it
+const MAX_REQUEST_LINE: u64 = 8 * 1024;
+const MAX_HEADERS: u64 = 16 * 1024;
+
+// A 1x1 transparent gif, which pages load to check that a CPS responder is running
+const PROBE_IMAGE: [u8; 43] = [
+    0x47, 0x49, 0x46, 0x38, 0x39, 0x61, 0x01, 0x00, 0x01, 0x00, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0xff, 0xff, 0xff, 0x21, 0xf9, 0x04, 0x01, 0x00, 0x00, 0x00, 0x00, 0x2c, 0x00, 0x00, 0x00, 0x00,
+    0x01, 0x00, 0x01, 0x00, 0x00, 0x02, 0x02, 0x44, 0x01, 0x00, 0x3b,
+];
+
+/// The user interface a [`CpsResponder`] uses during a login
+///
+/// This is implemented for any `FnMut(&str) -> Option<UnlockedIdentity>`
+/// closure, which cancels any question the server asks and aborts on any
+/// security warning.
+pub trait CpsHandler {
+    /// Called with the sqrl:// url the browser wants to log in to, returning
+    /// the unlocked identity to log in with, or None if the user cancelled
+    fn unlock_identity(&mut self, url: &str) -> Option<UnlockedIdentity>;
+
+    /// Called when the server asks the user a question
+    fn on_ask(&mut self, _ask: &Ask) -> AskAnswer {
+        AskAnswer::Cancel
+    }
+
+    /// Called when something about the login looks unsafe, such as a
+    /// possible man-in-the-middle
+    fn on_warning(&mut self, _warning: SecurityWarning) -> WarningAction {
+        WarningAction::Abort
+    }
+
+    /// Called once the login has finished, or failed with an error
+    fn on_finished(&mut self, _result: &Result<LoginOutcome>) {}
+}
+
+impl<F> CpsHandler for F
+where
+    F: FnMut(&str) -> Option<UnlockedIdentity>,
+{
+    fn unlock_identity(&mut self, url: &str) -> Option<UnlockedIdentity> {
+        self(url)
+    }
+}
+
+/// What a [`CpsResponder`] sends back to the browser
+#[derive(Clone, Debug, PartialEq)]
+pub enum CpsResponse {
+    /// Send the browser to the url
+    Redirect(String),
+    /// The image a page loads to check whether a CPS responder is running
+    ProbeImage,
+    /// There is nothing to send back
+    NotFound,
+}
+
+/// Answers the requests a browser sends to localhost:25519
+///
+/// A page links to `http://localhost:25519/` followed by its sqrl:// url,
+/// base64url encoded. The responder logs in to that url, asking the server
+/// to return the success url (opt=cps), and redirects the browser there. If
+/// the login doesn't succeed, the browser is sent to the url's can= value
+/// instead (if it has one).
+#[derive(Debug)]
+pub struct CpsResponder<T, H> {
+    transport: T,
+    handler: H,
+}
+
+impl<T: SqrlTransport, H: CpsHandler> CpsResponder<T, H> {
+    /// Create a responder that sends requests with the transport, and asks
+    /// the user for anything it needs through the handler
+    pub fn new(transport: T, handler: H) -> Self {
+        CpsResponder { transport, handler }
+    }
+
+    /// The transport the responder sends requests with
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    /// Listen on localhost:25519, over both IPv4 and IPv6, answering requests
+    /// forever
+    ///
+    /// Browsers may resolve localhost to either 127.0.0.1 or ::1. If the
+    /// machine has no IPv6 loopback address, only IPv4 is listened on.
+    pub fn listen(&mut self) -> Result<()> {
+        let ipv4 = TcpListener::bind((Ipv4Addr::LOCALHOST, CPS_PORT))?;
+        let ipv6 = match TcpListener::bind((Ipv6Addr::LOCALHOST, CPS_PORT)) {
+            Ok(listener) => listener,
+            Err(e) if ipv6_unavailable(&e) => return self.serve(&ipv4),
+            Err(e) => return Err(e.into()),
+        };
+
+        // Accept on both listeners at once, handing the connections to this
+        // thread to answer one at a time
+        let (sender, receiver) = mpsc::sync_channel(0);
+        thread::scope(|scope| {
+            for listener in [&ipv4, &ipv6] {
+                let sender = sender.clone();
+                scope.spawn(move || {
+                    for stream in listener.incoming() {
+                        if sender.send(stream).is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+            drop(sender);
+
+            self.serve_streams(receiver);
+        });
+
+        Ok(())
+    }
+
+    /// Answer the requests that arrive on the listener, forever
+    ///
+    /// A connection that can't be accepted is skipped.
+    pub fn serve(&mut self, listener: &TcpListener) -> Result<()> {
+        self.serve_streams(listener.incoming());
+        Ok(())
+    }
+
+    fn serve_streams(&mut self, streams: impl IntoIterator<Item = io::Result<TcpStream>>) {
+        for stream in streams {
+            // The client may have given up before its connection was
+            // accepted, which shouldn't stop the responder
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => {
+                    thread::sleep(ACCEPT_RETRY_DELAY);
+                    continue;
+                }
+            };
+
+            // A connection that never sends its request shouldn't hold up the responder
+            if stream.set_read_timeout(Some(READ_TIMEOUT)).is_err() {
+                continue;
+            }
+
+            // A browser going away mid request shouldn't stop the responder
+            let _ = self.handle_connection(&mut stream);
+        }
+    }
+
+    /// Read a single HTTP request from the connection and write the response
+    ///
+    /// A request with a request line over 8KiB, or headers over 16KiB, is
+    /// answered with [`CpsResponse::NotFound`].
+    pub fn handle_connection<S: Read + Write>(&mut self, stream: &mut S) -> Result<()> {
+        let mut reader = BufReader::new(&mut *stream).take(MAX_REQUEST_LINE);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line)?;
+        let mut too_long = reader.limit() == 0;
+
+        // The request is always a GET, so there's no body after the headers
+        reader.set_limit(MAX_HEADERS);
+        let mut header = String::new();
+        while !too_long && reader.read_line(&mut header)? > 0 && !header.trim().is_empty() {
+            header.clear();
+        }
+        too_long |= reader.limit() == 0;
+
+        let response = match request_line.split_whitespace().collect::<Vec<_>>()[..] {
+            ["GET", path, _] if !too_long => self.respond(path),
+            _ => CpsResponse::NotFound,
+        };
+        stream.write_all(&http_response(&response))?;
+        stream.flush()?;
+        Ok(())
+    }
+
+    /// Work out the response to a GET of the path
+    pub fn respond(&mut self, path: &str) -> CpsResponse {
+        let path = path.trim_start_matches('/');
+        if path.ends_with(".gif") {
+            return CpsResponse::ProbeImage;
+        }
+
+        let url = match decode(path) {
+            Some(url) if url.starts_with("sqrl://") => url,
+            _ => return CpsResponse::NotFound,
+        };
+        let cancel = cancel_url(&url).map_or(CpsResponse::NotFound, CpsResponse::Redirect);
+
+        let identity = match self.handler.unlock_identity(&url) {
+            Some(identity) => identity,
+            None => return cancel,
+        };
+        let result = self.log_in(&identity, &url);
+        self.handler.on_finished(&result);
+
+        let success_url = match result {
+            Ok(LoginOutcome::LoggedIn { success_url })
+            | Ok(LoginOutcome::IdentityAssociated { success_url })
+            | Ok(LoginOutcome::PreviousIdentityUpdated { success_url, .. })
+            | Ok(LoginOutcome::IdentityEnabled { success_url }) => success_url,
+            Ok(LoginOutcome::Failed(_)) | Err(_) => None,
+        };
+        success_url
+            .and_then(|url| https_url(&url))
+            .map_or(cancel, CpsResponse::Redirect)
+    }
+
+    fn log_in(&mut self, identity: &UnlockedIdentity, url: &str) -> Result<LoginOutcome> {
+        let mut session = LoginSession::new(identity, url, None)?;
+        session.set_client_provided_session(true);
+        session.set_same_device(true);
+
+        // Both callbacks need the handler, so share it between them
+        let handler = RefCell::new(&mut self.handler);
+        session.run_with_handlers(
+            &mut self.transport,
+            &mut |ask: &Ask| handler.borrow_mut().on_ask(ask),
+            &mut |warning: SecurityWarning| handler.borrow_mut().on_warning(warning),
+        )
+    }
+}
+
+// Whether binding to ::1 failed because the machine has no IPv6 loopback
+fn ipv6_unavailable(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::AddrNotAvailable | io::ErrorKind::Unsupported
+    )
+}
+
+fn decode(value: &str) -> Option<String> {
+    let decoded = BASE64_URL_SAFE_NO_PAD
+        .decode(value.trim_end_matches('='))
+        .ok()?;
+    String::from_utf8(decoded).ok()
+}
+
+// The can= value of a sqrl:// url is a base64url encoded url
+fn cancel_url(url: &str) -> Option<String> {
+    let can = Url::parse(url)
+        .ok()?
+        .query_pairs()
+        .find(|(key, _)| key == "can")
+        .map(|(_, value)| value.into_owned())?;
+    https_url(&decode(&can)?)
+}
+
+// Only send the browser to a well formed https url
+fn https_url(url: &str) -> Option<String> {
+    let url = Url::parse(url).ok()?;
+    match url.scheme() {
+        "https" => Some(url.into()),
+        _ => None,
+    }
+}
+
+fn http_response(response: &CpsResponse) -> Vec<u8> {
+    let mut http = match response {
+        CpsResponse::Redirect(url) => format!(
+            "HTTP/1.1 302 Found\r\nLocation: {}\r\nContent-Length: 0\r\n",
+            url
+        ),
+        CpsResponse::ProbeImage => format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: image/gif\r\nContent-Length: {}\r\n",
+            PROBE_IMAGE.len()
+        ),
+        CpsResponse::NotFound => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n".to_owned(),
+    }
+    .into_bytes();
+    http.extend_from_slice(b"Cache-Control: no-store\r\nConnection: close\r\n\r\n");
+    if *response == CpsResponse::ProbeImage {
+        http.extend_from_slice(&PROBE_IMAGE);
+    }
+    http
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::test_identity, transport::MockServer};
+    use std::io::Cursor;
+
+    const TEST_HOST: &str = "example.com";
+
+    fn cps_path(url: &str) -> String {
+        format!("/{}", BASE64_URL_SAFE_NO_PAD.encode(url))
+    }
+
+    // A connection that reads the browser's request from memory
+    struct TestStream {
+        request: Cursor<Vec<u8>>,
+        response: Vec<u8>,
+    }
+
+    impl Read for TestStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.request.read(buf)
+        }
+    }
+
+    impl Write for TestStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.response.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn send(request: String) -> String {
+        let mut responder = CpsResponder::new(MockServer::new(TEST_HOST), |_: &str| None);
+        let mut stream = TestStream {
+            request: Cursor::new(request.into_bytes()),
+            response: Vec::new(),
+        };
+        responder.handle_connection(&mut stream).unwrap();
+        String::from_utf8_lossy(&stream.response).into_owned()
+    }
+
+    #[test]
+    fn login_redirects_to_success_url() {
+        let identity = test_identity();
+        let mut server = MockServer::new(TEST_HOST);
+        let url = server.login_url();
+        let mut responder = CpsResponder::new(server, |_: &str| Some(identity.clone()));
+
+        assert_eq!(
+            responder.respond(&cps_path(&url)),
+            CpsResponse::Redirect("https://example.com/account".to_owned())
+        );
+        let identity_key = identity.get_public_identity(&url, None).unwrap();
+        assert!(responder.transport().account(&identity_key).is_some());
+    }
+
+    #[test]
+    fn cancelled_login_redirects_to_cancel_url() {
+        let mut server = MockServer::new(TEST_HOST);
+        let url = format!(
+            "{}&can={}",
+            server.login_url(),
+            BASE64_URL_SAFE_NO_PAD.encode("https://example.com/login")
+        );
+        let other_url = server.login_url();
+        let mut responder = CpsResponder::new(server, |_: &str| None);
+
+        assert_eq!(
+            responder.respond(&cps_path(&url)),
+            CpsResponse::Redirect("https://example.com/login".to_owned())
+        );
+        assert_eq!(
+            responder.respond(&cps_path(&other_url)),
+            CpsResponse::NotFound
+        );
+        assert_eq!(
+            responder.respond(&cps_path("https://example.com/")),
+            CpsResponse::NotFound
+        );
+        assert_eq!(
+            responder.respond("/1700000000000.gif"),
+            CpsResponse::ProbeImage
+        );
+    }
+
+    #[test]
+    fn login_over_http() {
+        let identity = test_identity();
+        let mut server = MockServer::new(TEST_HOST);
+        let url = server.login_url();
+        let mut responder = CpsResponder::new(server, |_: &str| Some(identity.clone()));
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let address = listener.local_addr().unwrap();
+        let browser = thread::spawn(move || {
+            let mut stream = TcpStream::connect(address).unwrap();
+            write!(
+                stream,
+                "GET {} HTTP/1.1\r\nHost: localhost:25519\r\n\r\n",
+                cps_path(&url)
+            )
+            .unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        });
+
+        let (mut stream, _) = listener.accept().unwrap();
+        responder.handle_connection(&mut stream).unwrap();
+        drop(stream);
+
+        let response = browser.join().unwrap();
+        assert!(response.starts_with("HTTP/1.1 302 Found\r\n"));
+        assert!(response.contains("\r\nLocation: https://example.com/account\r\n"));
+    }
+
+    #[test]
+    fn failed_accept_is_skipped() {
+        let mut responder = CpsResponder::new(MockServer::new(TEST_HOST), |_: &str| None);
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let address = listener.local_addr().unwrap();
+        let browser = thread::spawn(move || {
+            let mut stream = TcpStream::connect(address).unwrap();
+            write!(stream, "GET /1.gif HTTP/1.1\r\n\r\n").unwrap();
+            let mut response = Vec::new();
+            stream.read_to_end(&mut response).unwrap();
+            response
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        responder.serve_streams([Err(io::ErrorKind::ConnectionAborted.into()), Ok(stream)]);
+
+        let response = browser.join().unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with(&PROBE_IMAGE));
+    }
+
+    #[test]
+    fn oversized_requests_are_refused() {
+        let response = send("GET /1.gif HTTP/1.1\r\nHost: localhost\r\n\r\n".to_owned());
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+
+        let long_path = format!("/{}.gif", "a".repeat(MAX_REQUEST_LINE as usize));
+        let response = send(format!("GET {} HTTP/1.1\r\n\r\n", long_path));
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        let long_header = format!("Cookie: {}\r\n", "a".repeat(MAX_HEADERS as usize));
+        let response = send(format!("GET /1.gif HTTP/1.1\r\n{}\r\n", long_header));
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
@@ -3,25 +3,71 @@
 use scrypt::errors::{InvalidOutputLen, InvalidParams};
 use std::{fmt, num::ParseIntError, string::FromUtf8Error};
 
+/// The kind of error that occurred in the SQRL library
+#[derive(Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub enum SqrlErrorKind {
+    /// The password (or QuickPass hint) used to decrypt the identity was incorrect
+    WrongPassword,
+    /// The rescue code used to decrypt the identity unlock key was incorrect
+    WrongRescueCode,
+    /// The identity has to be unlocked with the rescue code to do this
+    RescueCodeRequired,
+    /// QuickPass is not enabled or has expired, so the full password is needed
+    QuickPassUnavailable,
+    /// The S4 data is invalid, starting at the byte offset
+    CorruptData {
+        /// The byte offset into the S4 data where the problem was found
+        offset: usize,
+    },
+    /// A block type was found that is not supported where it was used
+    UnsupportedBlock(u16),
+    /// The textual identity could not be decoded
+    InvalidTextualIdentity {
+        /// The line (0-based) of the textual identity that is invalid
+        line: usize,
+    },
+    /// The url is not a valid SQRL url
+    InvalidUrl,
+    /// An error occurred reading or writing data
+    Io,
+    /// The operation was cancelled by a progress handler
+    Cancelled,
+    /// Any other error
+    Other,
+}
+
 /// An error that can occur in the SQRL library
 pub struct SqrlError {
+    kind: SqrlErrorKind,
     error_message: String,
 }
 
 impl SqrlError {
     /// Create a new SqrlError with the string as error message
     pub fn new(error: String) -> Self {
+        SqrlError::with_kind(SqrlErrorKind::Other, error)
+    }
+
+    /// Create a new SqrlError of a specific kind with the string as error message
+    pub fn with_kind(kind: SqrlErrorKind, error: String) -> Self {
         SqrlError {
+            kind,
             error_message: error,
         }
     }
+
+    /// The kind of error that occurred
+    pub fn kind(&self) -> &SqrlErrorKind {
+        &self.kind
+    }
 }
 
 impl std::error::Error for SqrlError {}
 
 impl From<std::io::Error> for SqrlError {
     fn from(error: std::io::Error) -> Self {
-        SqrlError::new(error.to_string())
+        SqrlError::with_kind(SqrlErrorKind::Io, error.to_string())
     }
 }
 
@@ -33,7 +79,7 @@ impl From<std::array::TryFromSliceError> for SqrlError {
 
 impl From<url::ParseError> for SqrlError {
     fn from(error: url::ParseError) -> Self {
-        SqrlError::new(error.to_string())
+        SqrlError::with_kind(SqrlErrorKind::InvalidUrl, error.to_string())
     }
 }
 
@@ -91,6 +137,13 @@ impl From<sqrl_protocol::error::SqrlError> for SqrlError {
     }
 }
 
+#[cfg(feature = "async")]
+impl From<tokio::task::JoinError> for SqrlError {
+    fn from(value: tokio::task::JoinError) -> Self {
+        SqrlError::new(value.to_string())
+    }
+}
+
 impl fmt::Display for SqrlError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{}", self.error_message)
@@ -1,21 +1,35 @@
 use crate::{
     common::en_hash,
     config_options_to_u16,
-    error::SqrlError,
+    error::{SqrlError, SqrlErrorKind},
+    progress::ProgressHandler,
     readable_vector::ReadableVector,
-    scrypt_config::{en_scrypt, mut_en_scrypt, ScryptConfig},
+    scrypt_config::{en_scrypt, mut_en_scrypt, ScryptConfig, ScryptPolicy},
+    secret::Secret,
     writable_datablock::WritableDataBlock,
-    AesVerificationData, ConfigOptions, DataType, IdentityKey, IdentityUnlockKeys, Result,
+    AesVerificationData, ConfigOptions, DataType, IdentityKey, Result,
 };
 use aes_gcm::{
-    aead::{AeadMut, OsRng, Payload},
+    aead::{AeadMut, Payload},
     Aes256Gcm, KeyInit,
 };
 use byteorder::{LittleEndian, WriteBytesExt};
-use ed25519_dalek::{SigningKey, VerifyingKey};
-use rand::{prelude::StdRng, RngCore, SeedableRng};
+use rand_core::CryptoRngCore;
 use std::{collections::VecDeque, convert::TryInto, io::Write};
-use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/// The configuration settings to change, leaving any that are None as they were
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SettingsUpdate {
+    /// The configuration options stored with the identity
+    pub option_flags: Option<Vec<ConfigOptions>>,
+    /// How many characters of the password to use for QuickPass
+    pub hint_length: Option<u8>,
+    /// How long (in seconds) EnScrypt runs when the password is set
+    pub pw_verify_sec: Option<u8>,
+    /// How long (in minutes) QuickPass stays usable when idle
+    pub idle_timeout_min: Option<u16>,
+}
 
 #[derive(Clone, Debug, PartialEq)]
 pub(crate) struct IdentityInformation {
@@ -33,37 +47,50 @@ pub(crate) struct IdentityInformation {
 impl IdentityInformation {
     pub(crate) fn new(
         password: &str,
-        identity_master_key: [u8; 32],
-        identity_lock_key: [u8; 32],
+        identity_master_key: &IdentityKey,
+        identity_lock_key: &IdentityKey,
+        policy: &ScryptPolicy,
+        rng: &mut dyn CryptoRngCore,
+        progress: &mut dyn ProgressHandler,
     ) -> Result<Self> {
         let mut config = IdentityInformation {
             aes_gcm_iv: [0; 12],
-            scrypt_config: ScryptConfig::new(),
+            scrypt_config: ScryptConfig::from_policy(policy, rng),
             option_flags: Vec::new(),
             hint_length: 0,
-            pw_verify_sec: 5,
+            pw_verify_sec: policy.password_time_sec,
             idle_timeout_min: 0,
-            identity_master_key,
+            identity_master_key: [0; 32],
             identity_lock_key: [0; 32],
             verification_data: [0; 16],
         };
-        config.update_keys(password, identity_master_key, identity_lock_key)?;
+        config.update_keys(
+            password,
+            identity_master_key,
+            identity_lock_key,
+            rng,
+            progress,
+        )?;
 
         Ok(config)
     }
 
     pub(crate) fn from_identity_unlock_key(
         password: &str,
-        identity_unlock_key: [u8; 32],
+        identity_unlock_key: &IdentityKey,
+        policy: &ScryptPolicy,
+        rng: &mut dyn CryptoRngCore,
+        progress: &mut dyn ProgressHandler,
     ) -> Result<Self> {
-        // From the identity unlock key, generate the identity lock key and identity master key
-        // NOTE: The identity_lock_key is the "public key" for an ECDHKA ED25519 where the private key is the identity unlock key
-        let identity_master_key = en_hash(&identity_unlock_key);
-        let secret_key = StaticSecret::from(identity_unlock_key);
-        let public_key = PublicKey::from(&secret_key);
-        let identity_lock_key = public_key.to_bytes();
-
-        Self::new(password, identity_master_key, identity_lock_key)
+        let keys = EncryptedKeyPair::from_identity_unlock_key(identity_unlock_key);
+        Self::new(
+            password,
+            &keys.identity_master_key,
+            &keys.identity_lock_key,
+            policy,
+            rng,
+            progress,
+        )
     }
 
     fn aad(&self) -> Result<Vec<u8>> {
@@ -80,40 +107,78 @@ impl IdentityInformation {
         Ok(result)
     }
 
-    pub(crate) fn decrypt_identity_master_key(&self, password: &str) -> Result<IdentityKey> {
-        let decrypted_data = self.decrypt(password)?;
+    pub(crate) fn decrypt_identity_master_key(
+        &self,
+        password: &str,
+        progress: &mut dyn ProgressHandler,
+    ) -> Result<Secret<IdentityKey>> {
+        let decrypted_data = self.decrypt(password, progress)?;
         Ok(decrypted_data.identity_master_key)
     }
 
+    #[cfg(test)]
     pub(crate) fn decrypt_identity_lock_key(&self, password: &str) -> Result<PublicKey> {
-        let decrypted_data = self.decrypt(password)?;
-        Ok(PublicKey::from(decrypted_data.identity_lock_key))
+        let decrypted_data = self.decrypt(password, &mut crate::progress::NoProgress)?;
+        Ok(PublicKey::from(*decrypted_data.identity_lock_key))
+    }
+
+    pub(crate) fn scrypt_config(&self) -> &ScryptConfig {
+        &self.scrypt_config
+    }
+
+    pub(crate) fn option_flags(&self) -> &[ConfigOptions] {
+        &self.option_flags
+    }
+
+    pub(crate) fn hint_length(&self) -> u8 {
+        self.hint_length
+    }
+
+    pub(crate) fn pw_verify_sec(&self) -> u8 {
+        self.pw_verify_sec
+    }
+
+    pub(crate) fn idle_timeout_min(&self) -> u16 {
+        self.idle_timeout_min
     }
 
-    pub(crate) fn verify(&self, password: &str) -> Result<()> {
-        self.decrypt(password)?;
+    pub(crate) fn verify(&self, password: &str, progress: &mut dyn ProgressHandler) -> Result<()> {
+        self.decrypt(password, progress)?;
         Ok(())
     }
 
+    // Start over with a new salt and the policy's cost parameters, so the next
+    // update_keys doesn't reuse the old EnScrypt settings
+    pub(crate) fn reset_scrypt_config(
+        &mut self,
+        policy: &ScryptPolicy,
+        rng: &mut dyn CryptoRngCore,
+    ) {
+        self.scrypt_config = ScryptConfig::from_policy(policy, rng);
+        self.pw_verify_sec = policy.password_time_sec;
+    }
+
     pub(crate) fn update_keys(
         &mut self,
         password: &str,
-        identity_master_key: [u8; 32],
-        identity_lock_key: [u8; 32],
+        identity_master_key: &IdentityKey,
+        identity_lock_key: &IdentityKey,
+        rng: &mut dyn CryptoRngCore,
+        progress: &mut dyn ProgressHandler,
     ) -> Result<()> {
-        let mut random = StdRng::from_entropy();
-        let mut to_encrypt = Vec::new();
-        to_encrypt.write_all(&identity_master_key)?;
-        to_encrypt.write_all(&identity_lock_key)?;
+        let mut to_encrypt = Secret::new(Vec::with_capacity(64));
+        to_encrypt.write_all(identity_master_key)?;
+        to_encrypt.write_all(identity_lock_key)?;
 
         let key = mut_en_scrypt(
             password.as_bytes(),
             &mut self.scrypt_config,
             self.pw_verify_sec,
+            progress,
         )?;
 
-        random.fill_bytes(&mut self.aes_gcm_iv);
-        let mut aes = Aes256Gcm::new(&key.into());
+        rng.fill_bytes(&mut self.aes_gcm_iv);
+        let mut aes = Aes256Gcm::new(key.as_slice().into());
         let payload = Payload {
             msg: &to_encrypt,
             aad: &self.aad()?,
@@ -133,73 +198,64 @@ impl IdentityInformation {
         Ok(())
     }
 
-    pub(crate) fn generate_server_unlock_and_verify_unlock_keys(
-        &self,
-        password: &str,
-    ) -> Result<IdentityUnlockKeys> {
-        // Get the identity lock key so we confirm the password first
-        let identity_lock_key = self.decrypt_identity_lock_key(password)?;
-
-        // Generate the random secret key and the server unlock key (the matching public key)
-        let random_key = EphemeralSecret::random_from_rng(OsRng);
-        let server_unlock_key = PublicKey::from(&random_key);
-
-        // Diffie-Hellman the random key with the identity lock key
-        // Take that shared secret key and use it to generate an ed25519 key pair
-        let shared_secret = random_key.diffie_hellman(&identity_lock_key);
-        let secret_key = SigningKey::from_bytes(shared_secret.as_bytes());
-        let verify_unlock_key = VerifyingKey::from(&secret_key);
-
-        Ok(IdentityUnlockKeys::new(
-            server_unlock_key,
-            verify_unlock_key,
-        ))
-    }
-
     pub(crate) fn change_password(
         &mut self,
         current_password: &str,
         new_password: &str,
+        rng: &mut dyn CryptoRngCore,
+        progress: &mut dyn ProgressHandler,
     ) -> Result<()> {
-        let decrypted_data = self.decrypt(current_password)?;
+        let decrypted_data = self.decrypt(current_password, progress)?;
         self.update_keys(
             new_password,
-            decrypted_data.identity_master_key,
-            decrypted_data.identity_lock_key,
+            &decrypted_data.identity_master_key,
+            &decrypted_data.identity_lock_key,
+            rng,
+            progress,
         )
     }
 
-    pub(crate) fn update_setings(
+    pub(crate) fn update_settings(
         &mut self,
         password: &str,
-        option_flags: Option<Vec<ConfigOptions>>,
-        hint_length: Option<u8>,
-        pw_verify_sec: Option<u8>,
-        idle_timeout_min: Option<u16>,
+        settings: SettingsUpdate,
+        rng: &mut dyn CryptoRngCore,
+        progress: &mut dyn ProgressHandler,
     ) -> Result<()> {
-        let decryted = self.decrypt(password)?;
+        let decryted = self.decrypt(password, progress)?;
 
-        if let Some(options) = option_flags {
-            self.option_flags = options;
+        // The settings are part of the AAD, so only keep them if re-encrypting succeeds
+        let mut updated = self.clone();
+        if let Some(options) = settings.option_flags {
+            updated.option_flags = options;
         }
-        if let Some(hint) = hint_length {
-            self.hint_length = hint;
+        if let Some(hint) = settings.hint_length {
+            updated.hint_length = hint;
         }
-        if let Some(verify) = pw_verify_sec {
-            self.pw_verify_sec = verify;
+        if let Some(verify) = settings.pw_verify_sec {
+            updated.pw_verify_sec = verify;
         }
-        if let Some(idle) = idle_timeout_min {
-            self.idle_timeout_min = idle;
+        if let Some(idle) = settings.idle_timeout_min {
+            updated.idle_timeout_min = idle;
         }
 
-        self.update_keys(
+        updated.update_keys(
             password,
-            decryted.identity_master_key,
-            decryted.identity_lock_key,
-        )
+            &decryted.identity_master_key,
+            &decryted.identity_lock_key,
+            rng,
+            progress,
+        )?;
+        *self = updated;
+
+        Ok(())
     }
 
-    fn decrypt(&self, password: &str) -> Result<EncryptedKeyPair> {
+    pub(crate) fn decrypt(
+        &self,
+        password: &str,
+        progress: &mut dyn ProgressHandler,
+    ) -> Result<EncryptedKeyPair> {
         let mut encrypted_data: Vec<u8> = Vec::new();
         for byte in self.identity_master_key {
             encrypted_data.push(byte);
@@ -211,31 +267,19 @@ impl IdentityInformation {
             encrypted_data.push(byte);
         }
 
-        let key = en_scrypt(password.as_bytes(), &self.scrypt_config)?;
-        let mut aes = Aes256Gcm::new(&key.into());
+        let key = en_scrypt(password.as_bytes(), &self.scrypt_config, progress)?;
+        let mut aes = Aes256Gcm::new(key.as_slice().into());
         let payload = Payload {
             msg: &encrypted_data,
             aad: &self.aad()?,
         };
 
-        let mut decrypted_data: [u8; 64] = [0; 64];
-        for (i, x) in aes
-            .decrypt(&self.aes_gcm_iv.into(), payload)?
-            .iter()
-            .enumerate()
-        {
-            decrypted_data[i] = *x;
-        }
-
-        let mut identity_master_key = [0; 32];
-        let mut identity_lock_key = [0; 32];
-        identity_master_key[..32].copy_from_slice(&decrypted_data[..32]);
-        identity_lock_key[..32].copy_from_slice(&decrypted_data[32..64]);
+        let decrypted_data =
+            Secret::new(aes.decrypt(&self.aes_gcm_iv.into(), payload).map_err(|_| {
+                SqrlError::with_kind(SqrlErrorKind::WrongPassword, "Invalid password".to_owned())
+            })?);
 
-        Ok(EncryptedKeyPair {
-            identity_master_key,
-            identity_lock_key,
-        })
+        EncryptedKeyPair::from_slice(&decrypted_data)
     }
 }
 
@@ -296,15 +340,49 @@ impl WritableDataBlock for IdentityInformation {
     }
 }
 
-#[derive(Debug, PartialEq)]
-struct EncryptedKeyPair {
-    identity_master_key: [u8; 32],
-    identity_lock_key: [u8; 32],
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct EncryptedKeyPair {
+    pub(crate) identity_master_key: Secret<IdentityKey>,
+    pub(crate) identity_lock_key: Secret<IdentityKey>,
+}
+
+impl EncryptedKeyPair {
+    pub(crate) fn from_slice(decrypted_data: &[u8]) -> Result<Self> {
+        if decrypted_data.len() < 64 {
+            return Err(SqrlError::new("Invalid decrypted key data".to_owned()));
+        }
+
+        let mut identity_master_key = Secret::new([0; 32]);
+        let mut identity_lock_key = Secret::new([0; 32]);
+        identity_master_key.copy_from_slice(&decrypted_data[..32]);
+        identity_lock_key.copy_from_slice(&decrypted_data[32..64]);
+
+        Ok(EncryptedKeyPair {
+            identity_master_key,
+            identity_lock_key,
+        })
+    }
+
+    pub(crate) fn from_identity_unlock_key(identity_unlock_key: &IdentityKey) -> Self {
+        // From the identity unlock key, generate the identity lock key and identity master key
+        // NOTE: The identity_lock_key is the "public key" for an ECDHKA ED25519 where the private key is the identity unlock key
+        let identity_master_key = Secret::new(en_hash(identity_unlock_key));
+        let secret_key = StaticSecret::from(*identity_unlock_key);
+        let identity_lock_key = Secret::new(PublicKey::from(&secret_key).to_bytes());
+
+        EncryptedKeyPair {
+            identity_master_key,
+            identity_lock_key,
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::progress::NoProgress;
+    use aes_gcm::aead::OsRng;
+    use rand::RngCore;
 
     const TEST_PASSWORD: &str = "password";
 
@@ -313,8 +391,15 @@ mod tests {
         let mut identity_lock_key = [0; 32];
         OsRng.fill_bytes(&mut identity_lock_key);
 
-        let identity_information =
-            IdentityInformation::new(TEST_PASSWORD, [0; 32], identity_lock_key).unwrap();
+        let identity_information = IdentityInformation::new(
+            TEST_PASSWORD,
+            &[0; 32],
+            &identity_lock_key,
+            &ScryptPolicy::default(),
+            &mut OsRng,
+            &mut NoProgress,
+        )
+        .unwrap();
         let decrypted = identity_information
             .decrypt_identity_lock_key(TEST_PASSWORD)
             .unwrap();
@@ -326,12 +411,19 @@ mod tests {
         let mut identity_master_key = [0; 32];
         OsRng.fill_bytes(&mut identity_master_key);
 
-        let identity_information =
-            IdentityInformation::new(TEST_PASSWORD, identity_master_key, [0; 32]).unwrap();
+        let identity_information = IdentityInformation::new(
+            TEST_PASSWORD,
+            &identity_master_key,
+            &[0; 32],
+            &ScryptPolicy::default(),
+            &mut OsRng,
+            &mut NoProgress,
+        )
+        .unwrap();
         let decrypted = identity_information
-            .decrypt_identity_master_key(TEST_PASSWORD)
+            .decrypt_identity_master_key(TEST_PASSWORD, &mut NoProgress)
             .unwrap();
-        assert_eq!(decrypted, identity_master_key)
+        assert_eq!(*decrypted, identity_master_key)
     }
 
     #[test]
@@ -341,11 +433,18 @@ mod tests {
         OsRng.fill_bytes(&mut identity_master_key);
         OsRng.fill_bytes(&mut identity_lock_key);
 
-        let mut identity_information =
-            IdentityInformation::new(TEST_PASSWORD, [0; 32], [0; 32]).unwrap();
+        let mut identity_information = IdentityInformation::new(
+            TEST_PASSWORD,
+            &[0; 32],
+            &[0; 32],
+            &ScryptPolicy::default(),
+            &mut OsRng,
+            &mut NoProgress,
+        )
+        .unwrap();
         assert_eq!(
-            identity_information
-                .decrypt_identity_master_key(TEST_PASSWORD)
+            *identity_information
+                .decrypt_identity_master_key(TEST_PASSWORD, &mut NoProgress)
                 .unwrap(),
             [0; 32]
         );
@@ -357,11 +456,17 @@ mod tests {
         );
 
         identity_information
-            .update_keys(TEST_PASSWORD, identity_master_key, identity_lock_key)
+            .update_keys(
+                TEST_PASSWORD,
+                &identity_master_key,
+                &identity_lock_key,
+                &mut OsRng,
+                &mut NoProgress,
+            )
             .unwrap();
         assert_eq!(
-            identity_information
-                .decrypt_identity_master_key(TEST_PASSWORD)
+            *identity_information
+                .decrypt_identity_master_key(TEST_PASSWORD, &mut NoProgress)
                 .unwrap(),
             identity_master_key
         );
@@ -381,15 +486,25 @@ mod tests {
         OsRng.fill_bytes(&mut identity_master_key);
         OsRng.fill_bytes(&mut identity_lock_key);
 
-        let mut identity_information =
-            IdentityInformation::new(TEST_PASSWORD, identity_master_key, identity_lock_key)
-                .unwrap();
-        let decryted = identity_information.decrypt(TEST_PASSWORD).unwrap();
+        let mut identity_information = IdentityInformation::new(
+            TEST_PASSWORD,
+            &identity_master_key,
+            &identity_lock_key,
+            &ScryptPolicy::default(),
+            &mut OsRng,
+            &mut NoProgress,
+        )
+        .unwrap();
+        let decryted = identity_information
+            .decrypt(TEST_PASSWORD, &mut NoProgress)
+            .unwrap();
 
         identity_information
-            .change_password(TEST_PASSWORD, "password2")
+            .change_password(TEST_PASSWORD, "password2", &mut OsRng, &mut NoProgress)
+            .unwrap();
+        let decryted2 = identity_information
+            .decrypt("password2", &mut NoProgress)
             .unwrap();
-        let decryted2 = identity_information.decrypt("password2").unwrap();
         assert_eq!(decryted, decryted2);
     }
 }
@@ -1,6 +1,10 @@
 use crate::{
+    common::unlock_request_signing_key,
+    error::{SqrlError, SqrlErrorKind},
+    progress::{NoProgress, ProgressHandler},
     readable_vector::ReadableVector,
-    scrypt_config::{en_scrypt, mut_en_scrypt, ScryptConfig},
+    scrypt_config::{en_scrypt, mut_en_scrypt, ScryptConfig, ScryptPolicy},
+    secret::Secret,
     writable_datablock::WritableDataBlock,
     AesVerificationData, DataType, IdentityKey, Result, EMPTY_NONCE,
 };
@@ -12,11 +16,9 @@ use byteorder::{LittleEndian, WriteBytesExt};
 use ed25519_dalek::SigningKey;
 use num_bigint::BigUint;
 use num_traits::ToPrimitive;
-use rand::{prelude::StdRng, RngCore, SeedableRng};
+use rand_core::CryptoRngCore;
 use std::{collections::VecDeque, convert::TryInto, io::Write};
-use x25519_dalek::{PublicKey, StaticSecret};
 
-const RESCUE_CODE_SCRYPT_TIME: u8 = 5;
 const RESCUE_CODE_ALPHABET: [char; 10] = ['0', '1', '2', '3', '4', '5', '6', '7', '8', '9'];
 
 #[derive(Clone, Debug, PartialEq)]
@@ -27,14 +29,25 @@ pub(crate) struct IdentityUnlockData {
 }
 
 impl IdentityUnlockData {
-    pub(crate) fn new(identity_unlock_key: IdentityKey) -> Result<(Self, String)> {
+    pub(crate) fn new(
+        identity_unlock_key: &IdentityKey,
+        policy: &ScryptPolicy,
+        rng: &mut dyn CryptoRngCore,
+        progress: &mut dyn ProgressHandler,
+    ) -> Result<(Self, Secret<String>)> {
         let mut identity_unlock = IdentityUnlockData {
-            scrypt_config: ScryptConfig::new(),
+            scrypt_config: ScryptConfig::from_policy(policy, rng),
             identity_unlock_key: [0; 32],
             verification_data: [0; 16],
         };
 
-        let (rescue_code, _) = identity_unlock.update_unlock_key("", identity_unlock_key)?;
+        let (rescue_code, _) = identity_unlock.update_unlock_key(
+            "",
+            identity_unlock_key,
+            policy.rescue_code_time_sec,
+            rng,
+            progress,
+        )?;
 
         Ok((identity_unlock, rescue_code))
     }
@@ -42,25 +55,30 @@ impl IdentityUnlockData {
     pub(crate) fn update_unlock_key(
         &mut self,
         previous_rescue_code: &str,
-        identity_unlock_key: IdentityKey,
-    ) -> Result<(String, IdentityKey)> {
-        let mut previous_identity_key = [0; 32];
-        if self.identity_unlock_key != previous_identity_key {
-            previous_identity_key = self.decrypt_identity_unlock_key(previous_rescue_code)?;
+        identity_unlock_key: &IdentityKey,
+        rescue_code_time_sec: u8,
+        rng: &mut dyn CryptoRngCore,
+        progress: &mut dyn ProgressHandler,
+    ) -> Result<(Secret<String>, Secret<IdentityKey>)> {
+        let mut previous_identity_key = Secret::new([0; 32]);
+        if self.identity_unlock_key != *previous_identity_key {
+            previous_identity_key =
+                self.decrypt_identity_unlock_key(previous_rescue_code, progress)?;
         }
 
-        let rescue_code = generate_rescue_code();
+        let rescue_code = generate_rescue_code(rng);
         let decoded_rescue_code = decode_rescue_code(&rescue_code);
 
         let key = mut_en_scrypt(
             decoded_rescue_code.as_bytes(),
             &mut self.scrypt_config,
-            RESCUE_CODE_SCRYPT_TIME,
+            rescue_code_time_sec,
+            progress,
         )?;
 
-        let aes = Aes256Gcm::new(&key.into());
+        let aes = Aes256Gcm::new(key.as_slice().into());
         let payload = Payload {
-            msg: &identity_unlock_key,
+            msg: identity_unlock_key,
             aad: &self.aad()?,
         };
 
@@ -76,10 +94,14 @@ impl IdentityUnlockData {
         Ok((rescue_code, previous_identity_key))
     }
 
-    pub(crate) fn decrypt_identity_unlock_key(&self, rescue_code: &str) -> Result<IdentityKey> {
-        let mut unencrypted_data: [u8; 32] = [0; 32];
+    pub(crate) fn decrypt_identity_unlock_key(
+        &self,
+        rescue_code: &str,
+        progress: &mut dyn ProgressHandler,
+    ) -> Result<Secret<IdentityKey>> {
+        let mut unencrypted_data = Secret::new([0; 32]);
         let decoded_rescue_key = decode_rescue_code(rescue_code);
-        let key = en_scrypt(decoded_rescue_key.as_bytes(), &self.scrypt_config)?;
+        let key = en_scrypt(decoded_rescue_key.as_bytes(), &self.scrypt_config, progress)?;
 
         let mut encrypted_data: Vec<u8> = Vec::new();
         for byte in self.identity_unlock_key {
@@ -89,17 +111,20 @@ impl IdentityUnlockData {
             encrypted_data.push(byte);
         }
 
-        let aes = Aes256Gcm::new(&key.into());
+        let aes = Aes256Gcm::new(key.as_slice().into());
         let payload = Payload {
             msg: &encrypted_data,
             aad: &self.aad()?,
         };
 
-        for (i, x) in aes
-            .decrypt(&EMPTY_NONCE.into(), payload)?
-            .iter()
-            .enumerate()
-        {
+        let decrypted_data =
+            Secret::new(aes.decrypt(&EMPTY_NONCE.into(), payload).map_err(|_| {
+                SqrlError::with_kind(
+                    SqrlErrorKind::WrongRescueCode,
+                    "Invalid rescue code".to_owned(),
+                )
+            })?);
+        for (i, x) in decrypted_data.iter().enumerate() {
             unencrypted_data[i] = *x;
         }
 
@@ -111,13 +136,12 @@ impl IdentityUnlockData {
         rescue_code: &str,
         server_unlock_key: [u8; 32],
     ) -> Result<SigningKey> {
-        // Decrypt the identity unlock key and convert it to a DHKA secret key
-        let unlock_key = self.decrypt_identity_unlock_key(rescue_code)?;
-        let secret_key = StaticSecret::from(unlock_key);
+        let unlock_key = self.decrypt_identity_unlock_key(rescue_code, &mut NoProgress)?;
+        Ok(unlock_request_signing_key(&unlock_key, server_unlock_key))
+    }
 
-        // Do the key exchange and make it the secret key
-        let shared_secret = secret_key.diffie_hellman(&PublicKey::from(server_unlock_key));
-        Ok(SigningKey::from_bytes(shared_secret.as_bytes()))
+    pub(crate) fn scrypt_config(&self) -> &ScryptConfig {
+        &self.scrypt_config
     }
 
     fn aad(&self) -> Result<Vec<u8>> {
@@ -155,13 +179,13 @@ impl WritableDataBlock for IdentityUnlockData {
 }
 
 // Generate a random rescue code for use in encrypting data
-fn generate_rescue_code() -> String {
-    let mut random = StdRng::from_entropy();
-    let mut rescue_code_data: [u8; 32] = [0; 32];
-    random.fill_bytes(&mut rescue_code_data);
+fn generate_rescue_code(rng: &mut dyn CryptoRngCore) -> Secret<String> {
+    let mut rescue_code_data = Secret::new([0; 32]);
+    rng.fill_bytes(&mut *rescue_code_data);
 
-    let mut num = BigUint::from_bytes_be(&rescue_code_data);
-    let mut rescue_code = String::new();
+    let mut num = BigUint::from_bytes_be(&*rescue_code_data);
+    // Allocate all 24 digits and their hyphens up front, so the code is never copied when growing
+    let mut rescue_code = Secret::new(String::with_capacity(30));
     let mut count = 0;
     for _ in 0..24 {
         let remainder = &num % 10u8;
@@ -181,8 +205,8 @@ fn generate_rescue_code() -> String {
 }
 
 // Remove the hyphens from the rescue code
-fn decode_rescue_code(rescue_code: &str) -> String {
-    let mut result = String::new();
+fn decode_rescue_code(rescue_code: &str) -> Secret<String> {
+    let mut result = Secret::new(String::with_capacity(rescue_code.len()));
     for c in rescue_code.chars() {
         if c == '-' {
             continue;
@@ -196,6 +220,7 @@ fn decode_rescue_code(rescue_code: &str) -> String {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use rand::{prelude::StdRng, RngCore, SeedableRng};
 
     #[test]
     fn generate_and_unlock_with_rescue_code() {
@@ -203,13 +228,19 @@ mod tests {
         let mut identity_unlock_key: IdentityKey = [0; 32];
         random.fill_bytes(&mut identity_unlock_key);
 
-        let (unlock_data, rescue_code) = IdentityUnlockData::new(identity_unlock_key).unwrap();
+        let (unlock_data, rescue_code) = IdentityUnlockData::new(
+            &identity_unlock_key,
+            &ScryptPolicy::default(),
+            &mut random,
+            &mut NoProgress,
+        )
+        .unwrap();
         let decrypted_key = unlock_data
-            .decrypt_identity_unlock_key(&rescue_code)
+            .decrypt_identity_unlock_key(&rescue_code, &mut NoProgress)
             .unwrap();
 
         assert_eq!(
-            decrypted_key, identity_unlock_key,
+            *decrypted_key, identity_unlock_key,
             "Identity unlock keys do not match!"
         );
     }
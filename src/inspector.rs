@@ -0,0 +1,217 @@
+//! List what is stored in S4 data without needing the password
+
+use crate::{error::SqrlErrorKind, scrypt_config::ScryptConfig, ConfigOptions, Result, S4Blocks};
+use std::{
+    fs::File,
+    io::{BufReader, Read},
+    path::Path,
+};
+
+/// A block found in S4 data
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BlockSummary {
+    /// The block type (1 for the password block, 2 for the rescue code block
+    /// and 3 for previous identities)
+    pub block_type: u16,
+    /// The length of the block, as declared at its start
+    pub length: u16,
+}
+
+/// The EnScrypt parameters stored in the clear at the start of a block
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ScryptSummary {
+    /// The random salt
+    pub salt: [u8; 16],
+    /// The log N factor
+    pub log_n_factor: u8,
+    /// The number of EnScrypt iterations
+    pub iteration_count: u32,
+}
+
+/// Lists what is stored in S4 data without needing the password
+///
+/// Everything shown here is kept in the clear, as it is used as additional
+/// authenticated data (AAD) for the encrypted parts of each block. Corrupt
+/// data is listed as far as it could be parsed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct S4Inspector {
+    blocks: Vec<BlockSummary>,
+    password_scrypt: Option<ScryptSummary>,
+    rescue_code_scrypt: Option<ScryptSummary>,
+    option_flags: Option<Vec<ConfigOptions>>,
+    hint_length: Option<u8>,
+    pw_verify_sec: Option<u8>,
+    idle_timeout_min: Option<u16>,
+    previous_identity_count: u16,
+    corrupt_offset: Option<usize>,
+}
+
+impl S4Inspector {
+    /// Inspect the S4 data in a file
+    pub fn from_file<P: AsRef<Path>>(file_path: P) -> Result<Self> {
+        S4Inspector::read_from(BufReader::new(File::open(file_path)?))
+    }
+
+    /// Inspect the S4 binary data from a reader
+    ///
+    /// Only errors reading the data are returned. If the data is corrupt,
+    /// everything before the problem is still listed, and
+    /// [`S4Inspector::corrupt_offset`] says where it was found.
+    pub fn read_from<R: Read>(reader: R) -> Result<Self> {
+        let mut blocks = S4Blocks::default();
+        let corrupt_offset = match blocks.read_from(reader) {
+            Ok(()) => None,
+            Err(error) => match error.kind() {
+                SqrlErrorKind::CorruptData { offset } => Some(*offset),
+                _ => return Err(error),
+            },
+        };
+
+        let user_configuration = blocks.user_configuration.as_ref();
+        Ok(S4Inspector {
+            blocks: blocks.summaries,
+            password_scrypt: user_configuration.map(|info| summarize(info.scrypt_config())),
+            rescue_code_scrypt: blocks
+                .identity_unlock
+                .as_ref()
+                .map(|unlock| summarize(unlock.scrypt_config())),
+            option_flags: user_configuration.map(|info| info.option_flags().to_vec()),
+            hint_length: user_configuration.map(|info| info.hint_length()),
+            pw_verify_sec: user_configuration.map(|info| info.pw_verify_sec()),
+            idle_timeout_min: user_configuration.map(|info| info.idle_timeout_min()),
+            previous_identity_count: blocks
+                .previous_identities
+                .as_ref()
+                .map_or(0, |previous| previous.edition()),
+            corrupt_offset,
+        })
+    }
+
+    /// Every block in the data, in the order they were found
+    pub fn blocks(&self) -> &[BlockSummary] {
+        &self.blocks
+    }
+
+    /// The EnScrypt parameters for the password block, if it was found
+    pub fn password_scrypt(&self) -> Option<&ScryptSummary> {
+        self.password_scrypt.as_ref()
+    }
+
+    /// The EnScrypt parameters for the rescue code block, if it was found
+    pub fn rescue_code_scrypt(&self) -> Option<&ScryptSummary> {
+        self.rescue_code_scrypt.as_ref()
+    }
+
+    /// The configuration options set for the identity, if the password block
+    /// was found
+    pub fn option_flags(&self) -> Option<&[ConfigOptions]> {
+        self.option_flags.as_deref()
+    }
+
+    /// The number of password characters used as the QuickPass hint, if the
+    /// password block was found
+    pub fn hint_length(&self) -> Option<u8> {
+        self.hint_length
+    }
+
+    /// The number of seconds EnScrypt was run for when the password was set,
+    /// if the password block was found
+    pub fn pw_verify_sec(&self) -> Option<u8> {
+        self.pw_verify_sec
+    }
+
+    /// The number of idle minutes before the QuickPass hint expires, if the
+    /// password block was found
+    pub fn idle_timeout_min(&self) -> Option<u16> {
+        self.idle_timeout_min
+    }
+
+    /// The number of previous identities stored (the edition of the previous
+    /// identity block)
+    pub fn previous_identity_count(&self) -> u16 {
+        self.previous_identity_count
+    }
+
+    /// The byte offset where the data stopped making sense, or None if every
+    /// block was parsed
+    pub fn corrupt_offset(&self) -> Option<usize> {
+        self.corrupt_offset
+    }
+}
+
+fn summarize(config: &ScryptConfig) -> ScryptSummary {
+    ScryptSummary {
+        salt: config.random_salt,
+        log_n_factor: config.log_n_factor,
+        iteration_count: config.iteration_factor.unwrap_or(0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::test_client;
+
+    #[test]
+    fn inspect_test_file() {
+        let inspector =
+            S4Inspector::from_file("test_resources/Spec-Vectors-Identity.sqrl").unwrap();
+        assert_eq!(
+            inspector.blocks(),
+            &[
+                BlockSummary {
+                    block_type: 1,
+                    length: 125,
+                },
+                BlockSummary {
+                    block_type: 2,
+                    length: 73,
+                },
+            ]
+        );
+        let password_scrypt = inspector.password_scrypt().unwrap();
+        assert_eq!(password_scrypt.log_n_factor, 9);
+        assert_eq!(password_scrypt.iteration_count, 179);
+        assert_eq!(password_scrypt.salt[..2], [0xf5, 0x01]);
+        assert!(inspector
+            .option_flags()
+            .unwrap()
+            .contains(&ConfigOptions::WarnManInTheMiddle));
+        assert_eq!(inspector.hint_length(), Some(4));
+        assert_eq!(inspector.pw_verify_sec(), Some(5));
+        assert_eq!(inspector.idle_timeout_min(), Some(15));
+        assert_eq!(inspector.previous_identity_count(), 0);
+        assert_eq!(inspector.corrupt_offset(), None);
+    }
+
+    #[test]
+    fn inspect_rekeyed_identity() {
+        let (mut client, rescue_code) = test_client();
+        client.rekey_identity("password", &rescue_code).unwrap();
+        let mut data = Vec::new();
+        client.write_to(&mut data).unwrap();
+
+        let inspector = S4Inspector::read_from(data.as_slice()).unwrap();
+        assert_eq!(inspector.blocks().len(), 3);
+        assert_eq!(inspector.blocks()[2].block_type, 3);
+        assert_eq!(inspector.rescue_code_scrypt().unwrap().iteration_count, 1);
+        assert_eq!(inspector.previous_identity_count(), 1);
+    }
+
+    #[test]
+    fn inspect_corrupt_data() {
+        let data = std::fs::read("test_resources/Spec-Vectors-Identity.sqrl").unwrap();
+
+        // The rescue code block is cut short, so only the password block is listed
+        let inspector = S4Inspector::read_from(&data[..data.len() - 1]).unwrap();
+        assert_eq!(inspector.blocks().len(), 1);
+        assert!(inspector.password_scrypt().is_some());
+        assert_eq!(inspector.rescue_code_scrypt(), None);
+        assert_eq!(inspector.corrupt_offset(), Some(133));
+
+        let inspector = S4Inspector::read_from(&b"SQRLDATX"[..]).unwrap();
+        assert!(inspector.blocks().is_empty());
+        assert_eq!(inspector.hint_length(), None);
+        assert_eq!(inspector.corrupt_offset(), Some(0));
+    }
+}
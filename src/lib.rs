@@ -3,15 +3,47 @@
 //! <https://grc.com/sqrl>
 
 #![deny(missing_docs)]
+pub mod ask;
+#[cfg(feature = "async")]
+mod async_client;
 pub mod common;
+#[cfg(feature = "cps")]
+pub mod cps;
 pub mod error;
 mod identity_information;
 mod identity_unlock;
+mod inspector;
+pub mod login;
+#[cfg(any(test, feature = "server"))]
+mod mock_server;
+#[cfg(any(test, feature = "server"))]
+pub mod nut;
+mod options;
 mod previous_identity;
+pub mod progress;
+mod quick_pass;
 mod readable_vector;
 mod scrypt_config;
+mod secret;
+pub mod security;
+#[cfg(any(test, feature = "server"))]
+pub mod server;
+#[cfg(any(test, feature = "server"))]
+mod sqrl_server;
+#[cfg(test)]
+pub(crate) mod test_util;
+pub mod transport;
+mod unknown_block;
+mod unlocked_identity;
 mod writable_datablock;
 
+pub use identity_information::SettingsUpdate;
+pub use inspector::{BlockSummary, S4Inspector, ScryptSummary};
+pub use options::OperationOptions;
+pub use scrypt_config::{ScryptBlock, ScryptMinimum, ScryptPolicy, ScryptWarning};
+pub use secret::Secret;
+pub use unlocked_identity::UnlockedIdentity;
+
 extern crate aes_gcm;
 extern crate base64;
 extern crate byteorder;
@@ -27,24 +59,35 @@ extern crate url;
 extern crate x25519_dalek;
 
 use crate::{
-    common::{en_hash, slice_to_u8_32, IdentityUnlockKeys},
-    error::SqrlError,
+    common::{en_hash, IdentityUnlockKeys},
+    error::{SqrlError, SqrlErrorKind},
 };
-use aes_gcm::aead::OsRng;
 use base64::{prelude::BASE64_URL_SAFE, Engine};
 use byteorder::{LittleEndian, WriteBytesExt};
-use ed25519_dalek::{Signer, SigningKey, VerifyingKey};
+use ed25519_dalek::{SigningKey, VerifyingKey};
 use hmac::{Hmac, Mac};
 use num_bigint::BigUint;
 use num_traits::{FromPrimitive, ToPrimitive};
-use rand::RngCore;
+use rand_core::CryptoRngCore;
 use sha2::{Digest, Sha256};
 use sqrl_protocol::{client_request::ClientRequest, SqrlUrl};
-use std::{collections::VecDeque, fs::File, io::Write, result};
+use std::{
+    collections::VecDeque,
+    fmt,
+    fs::File,
+    io::{BufReader, ErrorKind, Read, Write},
+    path::Path,
+    result,
+};
 use x25519_dalek::{PublicKey, StaticSecret};
 use {
-    identity_information::IdentityInformation, identity_unlock::IdentityUnlockData,
-    previous_identity::PreviousIdentityData, readable_vector::ReadableVector,
+    identity_information::{EncryptedKeyPair, IdentityInformation},
+    identity_unlock::IdentityUnlockData,
+    previous_identity::PreviousIdentityData,
+    progress::{NoProgress, ProgressHandler},
+    quick_pass::QuickPass,
+    readable_vector::ReadableVector,
+    unknown_block::UnknownBlock,
     writable_datablock::WritableDataBlock,
 };
 
@@ -66,10 +109,10 @@ const TEXT_IDENTITY_ALPHABET: [char; 56] = [
 // + Load data from text based format
 // + Load data from base64url encoded format
 // - Sign request based on website and nut (and alternative identity information)
-// - Ability to decrypt code with base password and use "quick-password"
+// + Ability to decrypt code with base password and use "quick-password"
 // - Recover identity using unlock key
 // - Store previous identities and be able to access them
-// - Handle special "Ask" functionality
+// + Handle special "Ask" functionality
 
 /// A SQRL client
 ///
@@ -78,32 +121,94 @@ const TEXT_IDENTITY_ALPHABET: [char; 56] = [
 ///
 /// - Generating identities
 /// - Signing requests
-#[derive(Debug, PartialEq)]
+#[derive(Clone, PartialEq)]
 pub struct SqrlClient {
     user_configuration: IdentityInformation,
     identity_unlock: IdentityUnlockData,
     previous_identities: Option<PreviousIdentityData>,
+    quick_pass: Option<QuickPass>,
+    unknown_blocks: Vec<UnknownBlock>,
+    scrypt_policy: ScryptPolicy,
+}
+
+// Only print metadata, so that no key material (even encrypted) ends up in logs
+impl fmt::Debug for SqrlClient {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SqrlClient")
+            .field(
+                "has_previous_identities",
+                &self.previous_identities.is_some(),
+            )
+            .field("has_quick_pass", &self.quick_pass.is_some())
+            .field("unknown_blocks", &self.unknown_blocks.len())
+            .finish_non_exhaustive()
+    }
 }
 
 impl SqrlClient {
     /// Create a new SQRL client protecting the data with the password
-    pub fn new(password: &str) -> Result<(Self, String)> {
+    pub fn new(password: &str) -> Result<(Self, Secret<String>)> {
+        SqrlClient::new_with_options(password, OperationOptions::default())
+    }
+
+    /// Create a new SQRL client protecting the data with the password, using
+    /// the random number generator, progress handler and EnScrypt policy from
+    /// the options
+    ///
+    /// The policy is also used when the identity is rekeyed or recreated from
+    /// the rescue code.
+    /// ```rust
+    /// use sqrl_client::{OperationOptions, ScryptPolicy, SqrlClient};
+    ///
+    /// // A low iteration count makes tests fast, but should never be used for real identities
+    /// let options = OperationOptions {
+    ///     policy: Some(ScryptPolicy::with_iterations(1)),
+    ///     ..Default::default()
+    /// };
+    /// let (client, _) = SqrlClient::new_with_options("password", options).unwrap();
+    /// ```
+    pub fn new_with_options(
+        password: &str,
+        options: OperationOptions,
+    ) -> Result<(Self, Secret<String>)> {
+        options.run(|rng, progress, policy| {
+            let policy = policy.unwrap_or_default();
+            policy.validate()?;
+            SqrlClient::create(password, policy, rng, progress)
+        })
+    }
+
+    fn create(
+        password: &str,
+        scrypt_policy: ScryptPolicy,
+        rng: &mut dyn CryptoRngCore,
+        progress: &mut dyn ProgressHandler,
+    ) -> Result<(Self, Secret<String>)> {
         // Generate a random identity unlock key base
-        let mut identity_unlock_key: [u8; 32] = [0; 32];
-        OsRng.fill_bytes(&mut identity_unlock_key);
+        let mut identity_unlock_key = Secret::new([0; 32]);
+        rng.fill_bytes(&mut *identity_unlock_key);
 
         // Encrypt the identity unlock key with a random rescue code to return
-        let (identity_unlock, rescue_code) = IdentityUnlockData::new(identity_unlock_key)?;
+        let (identity_unlock, rescue_code) =
+            IdentityUnlockData::new(&identity_unlock_key, &scrypt_policy, rng, progress)?;
 
         // Encrypt the identity master key and identity lock key in
-        let user_configuration =
-            IdentityInformation::from_identity_unlock_key(password, identity_unlock_key)?;
+        let user_configuration = IdentityInformation::from_identity_unlock_key(
+            password,
+            &identity_unlock_key,
+            &scrypt_policy,
+            rng,
+            progress,
+        )?;
 
         Ok((
             SqrlClient {
                 user_configuration,
                 identity_unlock,
                 previous_identities: None,
+                quick_pass: None,
+                unknown_blocks: Vec::new(),
+                scrypt_policy,
             },
             rescue_code,
         ))
@@ -113,33 +218,91 @@ impl SqrlClient {
         identity_unlock: IdentityUnlockData,
         rescue_code: &str,
         new_password: &str,
+        scrypt_policy: ScryptPolicy,
+        rng: &mut dyn CryptoRngCore,
+        progress: &mut dyn ProgressHandler,
     ) -> Result<Self> {
-        let identity_unlock_key = identity_unlock.decrypt_identity_unlock_key(rescue_code)?;
+        let identity_unlock_key =
+            identity_unlock.decrypt_identity_unlock_key(rescue_code, progress)?;
 
         // Encrypt the identity master key and identity lock key in
-        let user_configuration =
-            IdentityInformation::from_identity_unlock_key(new_password, identity_unlock_key)?;
+        let user_configuration = IdentityInformation::from_identity_unlock_key(
+            new_password,
+            &identity_unlock_key,
+            &scrypt_policy,
+            rng,
+            progress,
+        )?;
 
         Ok(SqrlClient {
             user_configuration,
             identity_unlock,
             previous_identities: None,
+            quick_pass: None,
+            unknown_blocks: Vec::new(),
+            scrypt_policy,
         })
     }
 
     /// Recreate the SQRL data from a rescue code
     pub fn recreate_from_rescue_code(&self, rescue_code: &str, new_password: &str) -> Result<Self> {
+        self.recreate_from_rescue_code_with_options(
+            rescue_code,
+            new_password,
+            OperationOptions::default(),
+        )
+    }
+
+    /// Recreate the SQRL data from a rescue code, using the random number
+    /// generator, progress handler and EnScrypt policy from the options
+    ///
+    /// Without a policy in the options, the recreated identity keeps
+    /// [`SqrlClient::scrypt_policy`].
+    pub fn recreate_from_rescue_code_with_options(
+        &self,
+        rescue_code: &str,
+        new_password: &str,
+        options: OperationOptions,
+    ) -> Result<Self> {
+        options.run(|rng, progress, policy| {
+            let policy = match policy {
+                Some(policy) => {
+                    policy.validate()?;
+                    policy
+                }
+                None => self.scrypt_policy.clone(),
+            };
+            self.recreate(rescue_code, new_password, policy, rng, progress)
+        })
+    }
+
+    fn recreate(
+        &self,
+        rescue_code: &str,
+        new_password: &str,
+        scrypt_policy: ScryptPolicy,
+        rng: &mut dyn CryptoRngCore,
+        progress: &mut dyn ProgressHandler,
+    ) -> Result<Self> {
         let identity_unlock_key = self
             .identity_unlock
-            .decrypt_identity_unlock_key(rescue_code)?;
-
-        let user_configuration =
-            IdentityInformation::from_identity_unlock_key(new_password, identity_unlock_key)?;
+            .decrypt_identity_unlock_key(rescue_code, progress)?;
+
+        let user_configuration = IdentityInformation::from_identity_unlock_key(
+            new_password,
+            &identity_unlock_key,
+            &scrypt_policy,
+            rng,
+            progress,
+        )?;
 
         Ok(SqrlClient {
             user_configuration,
             identity_unlock: self.identity_unlock.clone(),
             previous_identities: self.previous_identities.clone(),
+            quick_pass: None,
+            unknown_blocks: self.unknown_blocks.clone(),
+            scrypt_policy,
         })
     }
 
@@ -151,17 +314,208 @@ impl SqrlClient {
     /// client.change_password("password", "new_password").unwrap();
     /// ```
     pub fn change_password(&mut self, current_password: &str, new_password: &str) -> Result<()> {
+        self.change_password_with_options(
+            current_password,
+            new_password,
+            OperationOptions::default(),
+        )
+    }
+
+    /// Change the password for the encrypted client data, using the random
+    /// number generator and progress handler from the options
+    pub fn change_password_with_options(
+        &mut self,
+        current_password: &str,
+        new_password: &str,
+        options: OperationOptions,
+    ) -> Result<()> {
+        options.run(|rng, progress, _| {
+            self.set_password(current_password, new_password, rng, progress)
+        })
+    }
+
+    fn set_password(
+        &mut self,
+        current_password: &str,
+        new_password: &str,
+        rng: &mut dyn CryptoRngCore,
+        progress: &mut dyn ProgressHandler,
+    ) -> Result<()> {
+        self.quick_pass = None;
         self.user_configuration
-            .change_password(current_password, new_password)
+            .change_password(current_password, new_password, rng, progress)
     }
 
     /// Verify that the password is the correct password
     pub fn verify_password(&mut self, password: &str) -> Result<()> {
-        self.user_configuration.verify(password)?;
+        self.verify_password_with_progress(password, &mut NoProgress)
+    }
+
+    /// Verify that the password is the correct password, reporting the
+    /// progress of EnScrypt to the handler
+    /// ```rust
+    /// use sqrl_client::{
+    ///     progress::{EnScryptProgress, ProgressAction},
+    ///     SqrlClient,
+    /// };
+    ///
+    /// let (mut client, _) = SqrlClient::new("password").unwrap();
+    /// client
+    ///     .verify_password_with_progress("password", &mut |progress: EnScryptProgress| {
+    ///         println!("{:?}", progress);
+    ///         ProgressAction::Continue
+    ///     })
+    ///     .unwrap();
+    /// ```
+    pub fn verify_password_with_progress<P: ProgressHandler>(
+        &mut self,
+        password: &str,
+        progress: &mut P,
+    ) -> Result<()> {
+        self.user_configuration.verify(password, progress)?;
         Ok(())
     }
 
+    /// Decrypt the identity with the password, returning a session that can be
+    /// used for multiple operations without running EnScrypt again
+    pub fn unlock(&self, password: &str) -> Result<UnlockedIdentity> {
+        self.unlock_with_progress(password, &mut NoProgress)
+    }
+
+    /// Decrypt the identity with the password, reporting the progress of
+    /// EnScrypt to the handler
+    pub fn unlock_with_progress<P: ProgressHandler>(
+        &self,
+        password: &str,
+        progress: &mut P,
+    ) -> Result<UnlockedIdentity> {
+        let decrypted = self.user_configuration.decrypt(password, progress)?;
+        self.to_unlocked_identity(decrypted)
+    }
+
+    /// Decrypt the identity with the rescue code instead of the password
+    ///
+    /// Unlike an identity unlocked with the password, this can sign unlock
+    /// requests (such as re-enabling a disabled identity) for the current
+    /// identity.
+    pub fn unlock_with_rescue_code(&self, rescue_code: &str) -> Result<UnlockedIdentity> {
+        self.unlock_with_rescue_code_with_progress(rescue_code, &mut NoProgress)
+    }
+
+    /// Decrypt the identity with the rescue code instead of the password,
+    /// reporting the progress of EnScrypt to the handler
+    pub fn unlock_with_rescue_code_with_progress<P: ProgressHandler>(
+        &self,
+        rescue_code: &str,
+        progress: &mut P,
+    ) -> Result<UnlockedIdentity> {
+        let identity_unlock_key = self
+            .identity_unlock
+            .decrypt_identity_unlock_key(rescue_code, progress)?;
+        let keys = EncryptedKeyPair::from_identity_unlock_key(&identity_unlock_key);
+        Ok(self
+            .to_unlocked_identity(keys)?
+            .with_identity_unlock_key(identity_unlock_key))
+    }
+
+    /// Unlock the identity with the full password and enable QuickPass
+    ///
+    /// If the identity has a hint length set, the decrypted keys are
+    /// re-encrypted in memory using the first `hint_length` characters of the
+    /// password, so that later unlocks can be done using
+    /// [`SqrlClient::unlock_with_hint`] until the idle timeout passes or an
+    /// incorrect hint is entered.
+    pub fn enable_quick_pass(&mut self, password: &str) -> Result<UnlockedIdentity> {
+        self.enable_quick_pass_with_options(password, OperationOptions::default())
+    }
+
+    /// Unlock the identity with the full password and enable QuickPass, using
+    /// the random number generator and progress handler from the options
+    pub fn enable_quick_pass_with_options(
+        &mut self,
+        password: &str,
+        options: OperationOptions,
+    ) -> Result<UnlockedIdentity> {
+        options.run(|rng, progress, _| {
+            self.quick_pass = None;
+            let decrypted = self.user_configuration.decrypt(password, progress)?;
+            let unlocked = self.to_unlocked_identity(decrypted)?;
+
+            let hint_length = self.user_configuration.hint_length();
+            if hint_length > 0 {
+                self.quick_pass = Some(QuickPass::new(
+                    quick_pass::get_hint(password, hint_length),
+                    &unlocked.keys(),
+                    self.user_configuration.idle_timeout_min(),
+                    rng,
+                    progress,
+                )?);
+            }
+
+            Ok(unlocked)
+        })
+    }
+
+    /// Unlock the identity using the QuickPass hint
+    ///
+    /// If the QuickPass has expired, or the hint is incorrect, the cached keys
+    /// are wiped and the full password needs to be used again.
+    pub fn unlock_with_hint(&mut self, hint: &str) -> Result<UnlockedIdentity> {
+        self.unlock_with_hint_with_progress(hint, &mut NoProgress)
+    }
+
+    /// Unlock the identity using the QuickPass hint, reporting the progress of
+    /// EnScrypt to the handler
+    ///
+    /// Cancelling leaves the QuickPass enabled.
+    pub fn unlock_with_hint_with_progress<P: ProgressHandler>(
+        &mut self,
+        hint: &str,
+        progress: &mut P,
+    ) -> Result<UnlockedIdentity> {
+        let quick_pass = self.quick_pass.as_mut().ok_or(SqrlError::with_kind(
+            SqrlErrorKind::QuickPassUnavailable,
+            "QuickPass is not enabled".to_owned(),
+        ))?;
+
+        let decrypted = match quick_pass.decrypt(hint, progress) {
+            Ok(decrypted) => decrypted,
+            Err(e) if e.kind() == &SqrlErrorKind::Cancelled => return Err(e),
+            Err(e) => {
+                self.quick_pass = None;
+                return Err(e);
+            }
+        };
+
+        self.to_unlocked_identity(decrypted)
+    }
+
+    /// Returns true if the identity can currently be unlocked using the QuickPass hint
+    ///
+    /// If the QuickPass has expired, the cached keys are wiped.
+    pub fn has_quick_pass(&mut self) -> bool {
+        if self
+            .quick_pass
+            .as_ref()
+            .is_some_and(|quick_pass| quick_pass.is_expired())
+        {
+            self.quick_pass = None;
+        }
+
+        self.quick_pass.is_some()
+    }
+
+    /// Wipe the QuickPass data from memory, requiring the full password to be used again
+    ///
+    /// This should be called when any of the ClearDataOn* events occur
+    pub fn clear_quick_pass(&mut self) {
+        self.quick_pass = None;
+    }
+
     /// Sign a client request with the key generated by the url and alternate identity
+    ///
+    /// The signatures are made over the encoding from
+    /// [`crate::common::encode_request`], so send the request with that.
     pub fn sign_request(
         &self,
         password: &str,
@@ -170,34 +524,8 @@ impl SqrlClient {
         request: &mut ClientRequest,
         previous_key_index: Option<usize>,
     ) -> Result<()> {
-        let sqrl_url = SqrlUrl::parse(url)?;
-        let private_key =
-            self.get_private_key(password, &sqrl_url.get_auth_domain(), alternate_identity)?;
-
-        request.client_params.identity_key = private_key.verifying_key();
-
-        if let Some(prev) = &self.previous_identities {
-            let key_index = previous_key_index.unwrap_or(0);
-            let identity_master_key = self
-                .user_configuration
-                .decrypt_identity_master_key(password)?;
-
-            if let Some(previous_key) =
-                prev.get_previous_identity(&identity_master_key, key_index)?
-            {
-                let previous_private_key = previous_key
-                    .get_private_key(&sqrl_url.get_auth_domain(), alternate_identity)?;
-                request.client_params.previous_identity_key =
-                    Some(previous_private_key.verifying_key());
-                request.previous_identity_signature =
-                    Some(previous_private_key.sign(request.get_signed_string().as_bytes()));
-            }
-        }
-
-        // Sign last, as we need to set the current and previous key ids
-        request.identity_signature = private_key.sign(request.get_signed_string().as_bytes());
-
-        Ok(())
+        self.unlock(password)?
+            .sign_request(url, alternate_identity, request, previous_key_index)
     }
 
     /// Get a sin value based on the url and alternate identity
@@ -208,63 +536,117 @@ impl SqrlClient {
         alternate_identity: Option<&str>,
         secret_index: &str,
     ) -> Result<String> {
-        let private_key = self.get_private_key(
+        self.get_secret_index_key_with_progress(
             password,
-            &SqrlUrl::parse(url)?.get_auth_domain(),
+            url,
             alternate_identity,
-        )?;
-        let hash = en_hash(&private_key.to_bytes());
-        let mut hmac = Hmac::<Sha256>::new_from_slice(&hash)?;
-        hmac.update(secret_index.as_bytes());
-        Ok(BASE64_URL_SAFE.encode(hmac.finalize().into_bytes()))
+            secret_index,
+            &mut NoProgress,
+        )
+    }
+
+    /// Get a sin value based on the url and alternate identity, reporting the
+    /// progress of EnScrypt to the handler
+    pub fn get_secret_index_key_with_progress<P: ProgressHandler>(
+        &self,
+        password: &str,
+        url: &str,
+        alternate_identity: Option<&str>,
+        secret_index: &str,
+        progress: &mut P,
+    ) -> Result<String> {
+        self.unlock_with_progress(password, progress)?
+            .get_secret_index_key(url, alternate_identity, secret_index)
     }
 
     /// Generate a new identity, storing the previous identity in the list of previous identities
-    pub fn rekey_identity(&mut self, password: &str, rescue_code: &str) -> Result<String> {
-        // Verify we can decrypt the current identity unlock key
+    ///
+    /// Both the password and rescue code blocks are encrypted with a new salt
+    /// and the EnScrypt cost parameters from [`SqrlClient::scrypt_policy`].
+    pub fn rekey_identity(&mut self, password: &str, rescue_code: &str) -> Result<Secret<String>> {
+        self.rekey_identity_with_options(password, rescue_code, OperationOptions::default())
+    }
+
+    /// Generate a new identity, storing the previous identity in the list of previous identities,
+    /// using the random number generator and progress handler from the options
+    ///
+    /// If the operation is cancelled, the identity is left unchanged.
+    pub fn rekey_identity_with_options(
+        &mut self,
+        password: &str,
+        rescue_code: &str,
+        options: OperationOptions,
+    ) -> Result<Secret<String>> {
+        options.run(|rng, progress, _| self.rekey(password, rescue_code, rng, progress))
+    }
+
+    fn rekey(
+        &mut self,
+        password: &str,
+        rescue_code: &str,
+        rng: &mut dyn CryptoRngCore,
+        progress: &mut dyn ProgressHandler,
+    ) -> Result<Secret<String>> {
+        // Verify both the password and rescue code before changing anything
+        let current_identity_master_key = self
+            .user_configuration
+            .decrypt_identity_master_key(password, progress)?;
         let current_identity_unlock_key = self
             .identity_unlock
-            .decrypt_identity_unlock_key(rescue_code)?;
+            .decrypt_identity_unlock_key(rescue_code, progress)?;
 
-        // Generate a new identity unlock key
-        let mut new_identity_unlock_key: [u8; 32] = [0; 32];
-        OsRng.fill_bytes(&mut new_identity_unlock_key);
+        // Generate a new identity unlock key, which is wiped when the StaticSecret is dropped
+        let new_identity_unlock_key = StaticSecret::random_from_rng(&mut *rng);
 
         // From the identity unlock key, generate the new identity lock key and identity master key
-        let new_identity_master_key = en_hash(&new_identity_unlock_key);
-        let secret_key = StaticSecret::from(new_identity_unlock_key);
-        let public_key = PublicKey::from(&secret_key);
-        let new_identity_lock_key = public_key.to_bytes();
+        let new_identity_master_key = Secret::new(en_hash(new_identity_unlock_key.as_bytes()));
+        let new_identity_lock_key = PublicKey::from(&new_identity_unlock_key).to_bytes();
 
         // Encrypt the identity unlock key with a random rescue code to return
-        let (new_identity_unlock, new_rescue_code) =
-            IdentityUnlockData::new(new_identity_unlock_key)?;
-        self.identity_unlock = new_identity_unlock;
+        let (new_identity_unlock, new_rescue_code) = IdentityUnlockData::new(
+            new_identity_unlock_key.as_bytes(),
+            &self.scrypt_policy,
+            rng,
+            progress,
+        )?;
 
         // Decrypt the previous identities and add the new one, re-encrypting with the new identity master key
-        if let Some(ref mut previous_identities) = self.previous_identities {
-            let current_identity_master_key = self
-                .user_configuration
-                .decrypt_identity_master_key(password)?;
-
-            previous_identities.rekey_previous_identities(
-                &current_identity_master_key,
-                &new_identity_master_key,
-                Some(current_identity_unlock_key),
-            )?;
-        } else {
-            let mut previous_identities = PreviousIdentityData::new();
-            previous_identities
-                .add_previous_identity(&new_identity_master_key, current_identity_unlock_key)?;
-            self.previous_identities = Some(previous_identities);
-        }
+        let previous_identities = match &self.previous_identities {
+            Some(previous_identities) => {
+                let mut previous_identities = previous_identities.clone();
+                previous_identities.rekey_previous_identities(
+                    current_identity_master_key.as_slice(),
+                    new_identity_master_key.as_slice(),
+                    Some(current_identity_unlock_key),
+                )?;
+                previous_identities
+            }
+            None => {
+                let mut previous_identities = PreviousIdentityData::new();
+                previous_identities.add_previous_identity(
+                    new_identity_master_key.as_slice(),
+                    current_identity_unlock_key,
+                )?;
+                previous_identities
+            }
+        };
 
-        self.user_configuration.update_keys(
+        let mut user_configuration = self.user_configuration.clone();
+        user_configuration.reset_scrypt_config(&self.scrypt_policy, rng);
+        user_configuration.update_keys(
             password,
-            new_identity_master_key,
-            new_identity_lock_key,
+            &new_identity_master_key,
+            &new_identity_lock_key,
+            rng,
+            progress,
         )?;
 
+        // Only update the identity once everything has succeeded
+        self.quick_pass = None;
+        self.identity_unlock = new_identity_unlock;
+        self.previous_identities = Some(previous_identities);
+        self.user_configuration = user_configuration;
+
         Ok(new_rescue_code)
     }
 
@@ -275,25 +657,54 @@ impl SqrlClient {
         url: &str,
         alternate_identity: Option<&str>,
     ) -> Result<VerifyingKey> {
-        Ok(self
-            .get_private_key(
-                password,
-                &SqrlUrl::parse(url)?.get_auth_domain(),
-                alternate_identity,
-            )?
-            .verifying_key())
+        self.get_public_identity_with_progress(password, url, alternate_identity, &mut NoProgress)
+    }
+
+    /// Retrieve the verifying key for a sqrl url, reporting the progress of
+    /// EnScrypt to the handler
+    pub fn get_public_identity_with_progress<P: ProgressHandler>(
+        &self,
+        password: &str,
+        url: &str,
+        alternate_identity: Option<&str>,
+        progress: &mut P,
+    ) -> Result<VerifyingKey> {
+        self.unlock_with_progress(password, progress)?
+            .get_public_identity(url, alternate_identity)
     }
 
     /// Generate the server unlock and verify unlock keys needed for unlocking
     /// an identity with a server
+    ///
+    /// The keys are not tied to a site: a new random pair is generated on
+    /// every call, to be sent with the ident that associates the identity.
+    /// The hostname and alternate identity are ignored.
+    #[deprecated(
+        note = "unlock the identity with `unlock` and use `UnlockedIdentity::generate_server_unlock_and_verify_unlock_keys`, or let `login::LoginSession` attach the keys to the ident"
+    )]
     pub fn generate_server_unlock_and_verify_unlock_keys(
         &self,
         password: &str,
         _hostname: &str,
         _alternate_identity: Option<&str>,
     ) -> Result<IdentityUnlockKeys> {
-        self.user_configuration
-            .generate_server_unlock_and_verify_unlock_keys(password)
+        self.unlock(password)?
+            .generate_server_unlock_and_verify_unlock_keys()
+    }
+
+    /// Generate the server unlock and verify unlock keys needed for unlocking
+    /// an identity with a server, reporting the progress of EnScrypt to the
+    /// handler
+    #[deprecated(
+        note = "unlock the identity with `unlock_with_progress` and use `UnlockedIdentity::generate_server_unlock_and_verify_unlock_keys`, or let `login::LoginSession` attach the keys to the ident"
+    )]
+    pub fn generate_server_unlock_and_verify_unlock_keys_with_progress<P: ProgressHandler>(
+        &self,
+        password: &str,
+        progress: &mut P,
+    ) -> Result<IdentityUnlockKeys> {
+        self.unlock_with_progress(password, progress)?
+            .generate_server_unlock_and_verify_unlock_keys()
     }
 
     /// Generate the signing key needed to sign an unlock identity request
@@ -307,6 +718,44 @@ impl SqrlClient {
     }
 
     /// Update the configuration settings stored in the encrypted file
+    pub fn update_config_settings(
+        &mut self,
+        password: &str,
+        option_flags: Option<Vec<ConfigOptions>>,
+        hint_length: Option<u8>,
+        pw_verify_sec: Option<u8>,
+        idle_timeout_min: Option<u16>,
+    ) -> Result<()> {
+        self.update_config_settings_with_options(
+            password,
+            SettingsUpdate {
+                option_flags,
+                hint_length,
+                pw_verify_sec,
+                idle_timeout_min,
+            },
+            OperationOptions::default(),
+        )
+    }
+
+    /// Update the configuration settings stored in the encrypted file, using
+    /// the random number generator and progress handler from the options
+    pub fn update_config_settings_with_options(
+        &mut self,
+        password: &str,
+        settings: SettingsUpdate,
+        options: OperationOptions,
+    ) -> Result<()> {
+        options.run(|rng, progress, _| {
+            self.user_configuration
+                .update_settings(password, settings, rng, progress)?;
+            self.quick_pass = None;
+            Ok(())
+        })
+    }
+
+    /// Update the configuration settings stored in the encrypted file
+    #[deprecated(note = "use `update_config_settings`")]
     pub fn upate_cofig_settings(
         &mut self,
         password: &str,
@@ -315,7 +764,7 @@ impl SqrlClient {
         pw_verify_sec: Option<u8>,
         idle_timeout_min: Option<u16>,
     ) -> Result<()> {
-        self.user_configuration.update_setings(
+        self.update_config_settings(
             password,
             option_flags,
             hint_length,
@@ -324,50 +773,142 @@ impl SqrlClient {
         )
     }
 
+    /// The configuration options stored with the identity
+    pub fn option_flags(&self) -> &[ConfigOptions] {
+        self.user_configuration.option_flags()
+    }
+
+    /// The EnScrypt cost parameters used when the identity is rekeyed or
+    /// recreated from the rescue code
+    pub fn scrypt_policy(&self) -> &ScryptPolicy {
+        &self.scrypt_policy
+    }
+
+    /// Change the EnScrypt cost parameters used when the identity is rekeyed
+    /// or recreated from the rescue code
+    pub fn set_scrypt_policy(&mut self, policy: ScryptPolicy) -> Result<()> {
+        policy.validate()?;
+        self.scrypt_policy = policy;
+        Ok(())
+    }
+
+    /// Check the EnScrypt cost parameters stored in the identity against a
+    /// minimum, returning a warning for each one that is too low
+    pub fn check_scrypt_parameters(&self, minimum: &ScryptMinimum) -> Vec<ScryptWarning> {
+        let mut warnings = Vec::new();
+        self.user_configuration.scrypt_config().check_minimum(
+            ScryptBlock::Password,
+            minimum.log_n_factor,
+            minimum.password_iterations,
+            &mut warnings,
+        );
+        self.identity_unlock.scrypt_config().check_minimum(
+            ScryptBlock::RescueCode,
+            minimum.log_n_factor,
+            minimum.rescue_code_iterations,
+            &mut warnings,
+        );
+
+        warnings
+    }
+
     /// Load SqrlClient from file
-    pub fn from_file(file_path: &str) -> Result<Self> {
-        SqrlClient::from_binary(convert_vec(std::fs::read(file_path)?))
+    pub fn from_file<P: AsRef<Path>>(file_path: P) -> Result<Self> {
+        SqrlClient::read_from(BufReader::new(File::open(file_path)?))
+    }
+
+    /// Load SqrlClient from file, also returning a warning for each stored
+    /// EnScrypt cost parameter that is below the minimum
+    pub fn from_file_with_minimum<P: AsRef<Path>>(
+        file_path: P,
+        minimum: &ScryptMinimum,
+    ) -> Result<(Self, Vec<ScryptWarning>)> {
+        let client = SqrlClient::from_file(file_path)?;
+        let warnings = client.check_scrypt_parameters(minimum);
+        Ok((client, warnings))
     }
 
     /// Save SqrlClient to file
-    pub fn to_file(&self, file_path: &str) -> Result<()> {
-        let mut file = File::create(file_path)?;
-        let data = self.to_binary()?;
-        file.write_all(&data)?;
+    pub fn to_file<P: AsRef<Path>>(&self, file_path: P) -> Result<()> {
+        self.write_to(File::create(file_path)?)
+    }
+
+    /// Load SqrlClient from a reader containing S4 binary data
+    ///
+    /// The [`ScryptPolicy`] used if the identity is later rekeyed or recreated
+    /// from the rescue code takes the log N factor and password time from the
+    /// password block. The rescue code time isn't stored, so it is left at the
+    /// default; use [`SqrlClient::set_scrypt_policy`] to change any of them.
+    pub fn read_from<R: Read>(reader: R) -> Result<Self> {
+        let mut blocks = S4Blocks::default();
+        blocks.read_from(reader)?;
+
+        // We need to make sure we have all of the data we expect
+        let user_access_check = blocks
+            .user_configuration
+            .ok_or(SqrlError::new("No key data found!".to_owned()))?;
+
+        let rescue_code_check = blocks
+            .identity_unlock
+            .ok_or(SqrlError::new("No rescue code data found!".to_owned()))?;
+
+        // Keep re-encrypting at the cost the identity was stored with, as far as the data records it
+        let mut scrypt_policy = ScryptPolicy {
+            log_n_factor: user_access_check.scrypt_config().log_n_factor,
+            ..Default::default()
+        };
+        if user_access_check.pw_verify_sec() > 0 {
+            scrypt_policy.password_time_sec = user_access_check.pw_verify_sec();
+        }
+
+        Ok(SqrlClient {
+            user_configuration: user_access_check,
+            identity_unlock: rescue_code_check,
+            previous_identities: blocks.previous_identities,
+            quick_pass: None,
+            unknown_blocks: blocks.unknown_blocks,
+            scrypt_policy,
+        })
+    }
 
+    /// Write SqrlClient as S4 binary data to a writer
+    pub fn write_to<W: Write>(&self, mut writer: W) -> Result<()> {
+        writer.write_all(&self.to_binary()?)?;
         Ok(())
     }
 
     /// Generate SqrlClient from base64 encoded data
     pub fn from_base64(input: &str) -> Result<Self> {
         // Confirm the beginning looks like what we expected
-        if input.len() < 8 || input[0..8] != FILE_HEADER.to_uppercase() {
-            return Err(SqrlError::new(format!(
-                "Invalid base64. Header text not valid: {}",
-                &input[0..8]
-            )));
+        if input.get(..FILE_HEADER.len()) != Some(FILE_HEADER.to_uppercase().as_str()) {
+            return Err(SqrlError::new(
+                "Invalid base64. Header text not valid".to_owned(),
+            ));
         }
 
         // Decode the rest using base64
-        let data = match BASE64_URL_SAFE.decode(&input[8..]) {
+        let data = match BASE64_URL_SAFE.decode(&input[FILE_HEADER.len()..]) {
             Ok(data) => data,
             Err(_) => return Err(SqrlError::new("Invalid binary data".to_owned())),
         };
 
-        let mut binary = convert_vec(data);
-
         // Add back the proper file header
-        for b in FILE_HEADER.bytes().rev() {
-            binary.push_front(b);
-        }
-
-        SqrlClient::from_binary(binary)
+        SqrlClient::read_from(FILE_HEADER.as_bytes().chain(data.as_slice()))
     }
 
     /// Convert SqrlClient to base64 encoding
+    ///
+    /// The output starts with the uppercase "SQRLDATA" header, followed by
+    /// the base64url encoded blocks, and can be read back with
+    /// [`SqrlClient::from_base64`].
     pub fn to_base64(&self) -> Result<String> {
+        // The header is written out as uppercase text, rather than being encoded
         let data = self.to_binary()?;
-        Ok(BASE64_URL_SAFE.encode(data))
+        Ok(format!(
+            "{}{}",
+            FILE_HEADER.to_uppercase(),
+            BASE64_URL_SAFE.encode(&data[FILE_HEADER.len()..])
+        ))
     }
 
     /// Take textual identity format and generate SqrlClient from it
@@ -376,14 +917,38 @@ impl SqrlClient {
         rescue_code: &str,
         new_password: &str,
     ) -> Result<Self> {
-        validate_textual_identity(input)?;
-        let mut data = decode_textual_identity(input)?;
-        if data.next_u16()? != 73 || DataType::from_binary(&mut data)? != DataType::RescueCode {
-            return Err(SqrlError::new("Invalid textual identity.".to_owned()));
-        }
+        SqrlClient::from_textual_identity_format_with_options(
+            input,
+            rescue_code,
+            new_password,
+            OperationOptions::default(),
+        )
+    }
 
-        let identity_unlock = IdentityUnlockData::from_binary(&mut data)?;
-        SqrlClient::from_identity_unlock(identity_unlock, rescue_code, new_password)
+    /// Take textual identity format and generate SqrlClient from it, using
+    /// the random number generator, progress handler and EnScrypt policy from
+    /// the options for the new password
+    ///
+    /// The policy is also used when the identity is rekeyed or recreated from
+    /// the rescue code.
+    pub fn from_textual_identity_format_with_options(
+        input: &str,
+        rescue_code: &str,
+        new_password: &str,
+        options: OperationOptions,
+    ) -> Result<Self> {
+        options.run(|rng, progress, policy| {
+            let policy = policy.unwrap_or_default();
+            policy.validate()?;
+            SqrlClient::from_identity_unlock(
+                parse_textual_identity(input)?,
+                rescue_code,
+                new_password,
+                policy,
+                rng,
+                progress,
+            )
+        })
     }
 
     /// Generate textual identity format of client data
@@ -391,111 +956,56 @@ impl SqrlClient {
         encode_textual_identity(self)
     }
 
-    fn get_private_key(
-        &self,
-        password: &str,
-        auth_domain: &str,
-        alternate_identity: Option<&str>,
-    ) -> Result<SigningKey> {
-        let key = self
-            .user_configuration
-            .decrypt_identity_master_key(password)?;
+    fn to_unlocked_identity(&self, decrypted: EncryptedKeyPair) -> Result<UnlockedIdentity> {
+        let previous_identity_unlock_keys = match &self.previous_identities {
+            Some(previous) => {
+                previous.decrypt_previous_identities(decrypted.identity_master_key.as_slice())?
+            }
+            None => VecDeque::new(),
+        };
 
-        key.get_private_key(auth_domain, alternate_identity)
+        Ok(UnlockedIdentity::new(
+            decrypted.identity_master_key,
+            decrypted.identity_lock_key,
+            previous_identity_unlock_keys,
+            self.user_configuration.option_flags().to_vec(),
+        ))
     }
 
-    fn from_binary(mut binary: VecDeque<u8>) -> Result<Self> {
-        match std::str::from_utf8(binary.next_sub_array(8)?.as_slice()) {
-            Ok(x) => {
-                if x != FILE_HEADER {
-                    return Err(SqrlError::new(format!(
-                        "Invalid file. Header text not valid: {}",
-                        x
-                    )));
-                }
-            }
-            Err(_) => {
-                return Err(SqrlError::new(
-                    "Invalid file. Could not parse header text.".to_string(),
-                ));
-            }
-        }
-
-        let mut user_configuration: Option<IdentityInformation> = None;
-        let mut identity_unlock: Option<IdentityUnlockData> = None;
-        let mut previous_identities: Option<PreviousIdentityData> = None;
-
-        loop {
-            if binary.is_empty() {
-                break;
-            }
-
-            // Make sure the length matches
-            // But add two after removing the first two bytes for size
-            let block_length = binary.next_u16()?;
-            if binary.len() + 2 < block_length.into() {
-                return Err(SqrlError::new("Invalid binary data".to_string()));
-            }
-
-            let block_type = DataType::from_binary(&mut binary)?;
-            match block_type {
-                DataType::UserAccess => {
-                    if user_configuration.is_some() {
-                        return Err(SqrlError::new(
-                            "Duplicate password information found!".to_owned(),
-                        ));
-                    }
-
-                    user_configuration = Some(IdentityInformation::from_binary(&mut binary)?)
-                }
-                DataType::RescueCode => {
-                    if identity_unlock.is_some() {
-                        return Err(SqrlError::new(
-                            "Duplicate rescue code data found!".to_owned(),
-                        ));
-                    }
-
-                    identity_unlock = Some(IdentityUnlockData::from_binary(&mut binary)?)
-                }
-                DataType::PreviousIdentity => {
-                    if previous_identities.is_some() {
-                        return Err(SqrlError::new(
-                            "Duplicate previous identity data found!".to_owned(),
-                        ));
-                    }
-
-                    previous_identities = Some(PreviousIdentityData::from_binary(&mut binary)?)
-                }
-            };
-        }
-
-        // We need to make sure we have all of the data we expect
-        let user_access_check =
-            user_configuration.ok_or(SqrlError::new("No key data found!".to_owned()))?;
-
-        let rescue_code_check =
-            identity_unlock.ok_or(SqrlError::new("No rescue code data found!".to_owned()))?;
-
-        Ok(SqrlClient {
-            user_configuration: user_access_check,
-            identity_unlock: rescue_code_check,
-            previous_identities,
-        })
-    }
-
-    fn to_binary(&self) -> Result<Vec<u8>> {
-        // Start by writing the header
-        let mut result = Vec::new();
-        for c in FILE_HEADER.bytes() {
-            result.push(c);
+    fn to_binary(&self) -> Result<Vec<u8>> {
+        // Start by writing the header
+        let mut result = Vec::new();
+        for c in FILE_HEADER.bytes() {
+            result.push(c);
         }
 
         // Make sure to write out all the sub data
-        self.user_configuration.to_binary(&mut result)?;
-        self.identity_unlock.to_binary(&mut result)?;
+        let mut blocks = Vec::new();
+        let mut block = Vec::new();
+        self.user_configuration.to_binary(&mut block)?;
+        blocks.push(block);
+        let mut block = Vec::new();
+        self.identity_unlock.to_binary(&mut block)?;
+        blocks.push(block);
         if let Some(previous) = &self.previous_identities {
-            previous.to_binary(&mut result)?;
+            let mut block = Vec::new();
+            previous.to_binary(&mut block)?;
+            blocks.push(block);
         };
+        // A block with nothing to store isn't written at all
+        blocks.retain(|block| !block.is_empty());
+
+        // Write back any blocks we don't understand exactly as they were read,
+        // in the same place they were found
+        for unknown in &self.unknown_blocks {
+            let mut block = Vec::new();
+            unknown.to_binary(&mut block)?;
+            blocks.insert(unknown.position().min(blocks.len()), block);
+        }
+
+        for block in blocks {
+            result.extend_from_slice(&block);
+        }
 
         Ok(result)
     }
@@ -578,11 +1088,19 @@ pub(crate) enum DataType {
 
 impl DataType {
     fn from_binary(binary: &mut VecDeque<u8>) -> Result<Self> {
-        match binary.next_u16()? {
-            1 => Ok(DataType::UserAccess),
-            2 => Ok(DataType::RescueCode),
-            3 => Ok(DataType::PreviousIdentity),
-            _ => Err(SqrlError::new("Invalid data type".to_owned())),
+        let value = binary.next_u16()?;
+        DataType::from_u16(value).ok_or(SqrlError::with_kind(
+            SqrlErrorKind::UnsupportedBlock(value),
+            format!("Invalid data type: {}", value),
+        ))
+    }
+
+    fn from_u16(value: u16) -> Option<Self> {
+        match value {
+            1 => Some(DataType::UserAccess),
+            2 => Some(DataType::RescueCode),
+            3 => Some(DataType::PreviousIdentity),
+            _ => None,
         }
     }
 
@@ -614,27 +1132,56 @@ impl GetKey for IdentityKey {
 
         let mut hmac = Hmac::<Sha256>::new_from_slice(self)?;
         hmac.update(data.as_bytes());
-        let mut temp = Vec::new();
-        for i in hmac.finalize().into_bytes() {
-            temp.push(i);
-        }
+        let private_key = Secret::new(<[u8; 32]>::from(hmac.finalize().into_bytes()));
 
-        Ok(SigningKey::from_bytes(&slice_to_u8_32(&temp)?))
+        Ok(SigningKey::from_bytes(&private_key))
     }
 }
 
 pub(crate) const EMPTY_NONCE: [u8; 12] = [0; 12];
 
+// The textual identity only holds the rescue code protected block
+fn parse_textual_identity(input: &str) -> Result<IdentityUnlockData> {
+    validate_textual_identity(input)?;
+    let mut data = decode_textual_identity(input)?;
+    if data.next_u16()? != 73 {
+        return Err(SqrlError::with_kind(
+            SqrlErrorKind::InvalidTextualIdentity { line: 0 },
+            "Invalid textual identity.".to_owned(),
+        ));
+    }
+
+    let block_type = DataType::from_binary(&mut data)?;
+    if block_type != DataType::RescueCode {
+        return Err(SqrlError::with_kind(
+            SqrlErrorKind::UnsupportedBlock(block_type as u16),
+            "Invalid textual identity. Expected rescue code data.".to_owned(),
+        ));
+    }
+
+    IdentityUnlockData::from_binary(&mut data)
+}
+
 fn validate_textual_identity(textual_identity: &str) -> Result<()> {
     let mut line_num: u8 = 0;
     for line in textual_identity.lines() {
         // Take each character and convert it to value
         let mut bytes: Vec<u8> = Vec::new();
         let trimmed_line = line.trim();
-        for c in trimmed_line[..trimmed_line.len() - 1].chars() {
+        if trimmed_line.is_empty() {
+            return Err(invalid_textual_identity(line_num.into()));
+        }
+
+        // The last character is the check character for the rest of the line
+        let mut chars = trimmed_line.chars();
+        let check = chars.next_back();
+        for c in chars {
             if c == ' ' {
                 continue;
             }
+            if !TEXT_IDENTITY_ALPHABET.contains(&c) {
+                return Err(invalid_textual_identity(line_num.into()));
+            }
             bytes.push(c as u8);
         }
         // Add the line number (0-based) as last
@@ -649,17 +1196,11 @@ fn validate_textual_identity(textual_identity: &str) -> Result<()> {
         let hash = BigUint::from_bytes_le(&output);
         if let Some(result) = (hash % 56u8).to_usize() {
             // If they don't match, the line is invalid
-            if TEXT_IDENTITY_ALPHABET[result]
-                != trimmed_line.chars().nth(trimmed_line.len() - 1).unwrap()
-            {
-                return Err(SqrlError::new(
-                    "Unable to decode textual identity format! Checksum fail.".to_string(),
-                ));
+            if Some(TEXT_IDENTITY_ALPHABET[result]) != check {
+                return Err(invalid_textual_identity(line_num.into()));
             }
         } else {
-            return Err(SqrlError::new(
-                "Unable to decode textual identity format! Checksum fail".to_string(),
-            ));
+            return Err(invalid_textual_identity(line_num.into()));
         }
 
         // Get ready for the next iteration
@@ -669,6 +1210,16 @@ fn validate_textual_identity(textual_identity: &str) -> Result<()> {
     Ok(())
 }
 
+fn invalid_textual_identity(line: usize) -> SqrlError {
+    SqrlError::with_kind(
+        SqrlErrorKind::InvalidTextualIdentity { line },
+        format!(
+            "Unable to decode textual identity format! Line {} is invalid.",
+            line
+        ),
+    )
+}
+
 fn encode_textual_identity(client: &SqrlClient) -> Result<String> {
     let mut textual_identity = String::new();
     let mut bytes: Vec<u8> = Vec::new();
@@ -752,10 +1303,16 @@ fn decode_textual_identity(textual_identity: &str) -> Result<VecDeque<u8>> {
     let mut power = BigUint::from_u8(0).unwrap();
     let zero = BigUint::from_u8(0).unwrap();
 
-    for line in textual_identity.lines() {
+    for (line_num, line) in textual_identity.lines().enumerate() {
         let trimmed_line = line.trim();
+        if trimmed_line.is_empty() {
+            return Err(invalid_textual_identity(line_num));
+        }
+
         // Go through the line from the back to the front (after removing the last character)
-        for c in trimmed_line[..trimmed_line.len() - 1].chars() {
+        let mut chars = trimmed_line.chars();
+        chars.next_back();
+        for c in chars {
             if c == ' ' {
                 continue;
             }
@@ -769,9 +1326,7 @@ fn decode_textual_identity(textual_identity: &str) -> Result<VecDeque<u8>> {
             if let Some(index) = TEXT_IDENTITY_ALPHABET.iter().position(|&r| r == c) {
                 data += index * &power;
             } else {
-                return Err(SqrlError::new(
-                    "Unable to decode textual identity!".to_string(),
-                ));
+                return Err(invalid_textual_identity(line_num));
             }
         }
     }
@@ -779,6 +1334,165 @@ fn decode_textual_identity(textual_identity: &str) -> Result<VecDeque<u8>> {
     Ok(convert_vec(data.to_bytes_le()))
 }
 
+// Everything read from S4 data, as far as it could be parsed
+#[derive(Default)]
+pub(crate) struct S4Blocks {
+    pub(crate) summaries: Vec<BlockSummary>,
+    pub(crate) user_configuration: Option<IdentityInformation>,
+    pub(crate) identity_unlock: Option<IdentityUnlockData>,
+    pub(crate) previous_identities: Option<PreviousIdentityData>,
+    pub(crate) unknown_blocks: Vec<UnknownBlock>,
+}
+
+impl S4Blocks {
+    // Read the header and then each block, keeping every block parsed before an error
+    pub(crate) fn read_from<R: Read>(&mut self, mut reader: R) -> Result<()> {
+        let mut header = [0; 8];
+        reader.read_exact(&mut header).map_err(|error| {
+            if error.kind() == ErrorKind::UnexpectedEof {
+                SqrlError::with_kind(
+                    SqrlErrorKind::CorruptData { offset: 0 },
+                    "Invalid file. Could not read header text.".to_string(),
+                )
+            } else {
+                error.into()
+            }
+        })?;
+
+        match std::str::from_utf8(&header) {
+            Ok(x) => {
+                if x != FILE_HEADER {
+                    return Err(SqrlError::with_kind(
+                        SqrlErrorKind::CorruptData { offset: 0 },
+                        format!("Invalid file. Header text not valid: {}", x),
+                    ));
+                }
+            }
+            Err(_) => {
+                return Err(SqrlError::with_kind(
+                    SqrlErrorKind::CorruptData { offset: 0 },
+                    "Invalid file. Could not parse header text.".to_string(),
+                ));
+            }
+        }
+
+        let mut offset = FILE_HEADER.len();
+        let mut position = 0;
+        while let Some(mut binary) = read_block(&mut reader, offset)? {
+            let block_start = offset;
+            let block_length = binary.next_u16()?;
+            let block_type = binary.next_u16()?;
+            offset += usize::from(block_length);
+            self.summaries.push(BlockSummary {
+                block_type,
+                length: block_length,
+            });
+
+            // Any failure while parsing a block means the data is corrupt at that point
+            let block_end = offset;
+            let corrupt = |binary: &VecDeque<u8>| corrupt_data(block_end - binary.len());
+            match DataType::from_u16(block_type) {
+                Some(DataType::UserAccess) => {
+                    if self.user_configuration.is_some() {
+                        return Err(SqrlError::with_kind(
+                            SqrlErrorKind::CorruptData {
+                                offset: block_start,
+                            },
+                            "Duplicate password information found!".to_owned(),
+                        ));
+                    }
+
+                    self.user_configuration = Some(
+                        IdentityInformation::from_binary(&mut binary)
+                            .map_err(|_| corrupt(&binary))?,
+                    )
+                }
+                Some(DataType::RescueCode) => {
+                    if self.identity_unlock.is_some() {
+                        return Err(SqrlError::with_kind(
+                            SqrlErrorKind::CorruptData {
+                                offset: block_start,
+                            },
+                            "Duplicate rescue code data found!".to_owned(),
+                        ));
+                    }
+
+                    self.identity_unlock = Some(
+                        IdentityUnlockData::from_binary(&mut binary)
+                            .map_err(|_| corrupt(&binary))?,
+                    )
+                }
+                Some(DataType::PreviousIdentity) => {
+                    if self.previous_identities.is_some() {
+                        return Err(SqrlError::with_kind(
+                            SqrlErrorKind::CorruptData {
+                                offset: block_start,
+                            },
+                            "Duplicate previous identity data found!".to_owned(),
+                        ));
+                    }
+
+                    self.previous_identities = Some(
+                        PreviousIdentityData::from_binary(&mut binary)
+                            .map_err(|_| corrupt(&binary))?,
+                    )
+                }
+                None => self.unknown_blocks.push(
+                    UnknownBlock::from_binary(block_type, block_length, position, &mut binary)
+                        .map_err(|_| corrupt(&binary))?,
+                ),
+            };
+            position += 1;
+        }
+
+        Ok(())
+    }
+}
+
+// Read the next length-prefixed block, or None if there is no more data
+fn read_block<R: Read>(reader: &mut R, offset: usize) -> Result<Option<VecDeque<u8>>> {
+    let mut length = [0; 2];
+    if reader.read(&mut length[..1])? == 0 {
+        return Ok(None);
+    }
+
+    // The block length includes the two bytes for the length itself
+    read_block_data(reader, &mut length[1..], offset)?;
+    let block_length = u16::from_le_bytes(length);
+    if block_length < 4 {
+        return Err(corrupt_data(offset));
+    }
+
+    let mut block = vec![0; block_length.into()];
+    block[..2].copy_from_slice(&length);
+    read_block_data(reader, &mut block[2..], offset)?;
+
+    Ok(Some(convert_vec(block)))
+}
+
+// Running out of data part way through a block means it is corrupt, but any
+// other error came from the reader itself
+fn read_block_data<R: Read>(reader: &mut R, buffer: &mut [u8], offset: usize) -> Result<()> {
+    reader.read_exact(buffer).map_err(|error| {
+        if error.kind() == ErrorKind::UnexpectedEof {
+            corrupt_data(offset)
+        } else {
+            error.into()
+        }
+    })
+}
+
+fn corrupt_data(offset: usize) -> SqrlError {
+    SqrlError::with_kind(
+        SqrlErrorKind::CorruptData { offset },
+        format!("Invalid binary data at offset {}", offset),
+    )
+}
+
+pub(crate) fn parse_url(url: &str) -> Result<SqrlUrl> {
+    SqrlUrl::parse(url).map_err(|e| SqrlError::with_kind(SqrlErrorKind::InvalidUrl, e.to_string()))
+}
+
 fn convert_vec(mut input: Vec<u8>) -> VecDeque<u8> {
     let mut new_vec = VecDeque::new();
     while let Some(x) = input.pop() {
@@ -791,12 +1505,23 @@ fn convert_vec(mut input: Vec<u8>) -> VecDeque<u8> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use progress::{EnScryptProgress, ProgressAction};
+    use rand::{prelude::StdRng, CryptoRng, RngCore, SeedableRng};
+    use test_util::test_client;
 
     const TEST_FILE_PATH: &str = "test_resources/Spec-Vectors-Identity.sqrl";
     const TEST_FILE_PASSWORD: &str = "Zingo-Bingo-Slingo-Dingo";
     const TEST_FILE_RESCUE_CODE: &str = "1198-8748-7132-2838-8318-7570";
     const TEST_FILE_TEXTUAL_IDENTITY: &str = "KKcC 3BaX akxc Xwbf xki7\nk7mF GHhg jQes gzWd 6TrK\nvMsZ dBtB pZbC zsz8 cUWj\nDtS2 ZK2s ZdAQ 8Yx3 iDyt\nQuXt CkTC y6gc qG8n Xfj9\nbHDA 422";
-    //const TEST_URL: &str = "sqrl://sqrl.grc.com/cli.sqrl?nut=fXkb4MBToCm7&can=aHR0cHM6Ly9zcXJsLmdyYy5jb20vZGVtbw";
+    const TEST_URL: &str =
+        "sqrl://sqrl.grc.com/cli.sqrl?nut=fXkb4MBToCm7&can=aHR0cHM6Ly9zcXJsLmdyYy5jb20vZGVtbw";
+
+    fn policy_options(policy: ScryptPolicy) -> OperationOptions<'static> {
+        OperationOptions {
+            policy: Some(policy),
+            ..Default::default()
+        }
+    }
 
     #[test]
     fn load_test_data() {
@@ -826,11 +1551,11 @@ mod tests {
         assert_eq!(
             second_client
                 .user_configuration
-                .decrypt_identity_master_key("password")
+                .decrypt_identity_master_key("password", &mut NoProgress)
                 .unwrap(),
             client
                 .user_configuration
-                .decrypt_identity_master_key(TEST_FILE_PASSWORD)
+                .decrypt_identity_master_key(TEST_FILE_PASSWORD, &mut NoProgress)
                 .unwrap()
         );
         assert_eq!(
@@ -845,6 +1570,29 @@ mod tests {
         )
     }
 
+    #[test]
+    fn base64_round_trip() {
+        let client = SqrlClient::from_file(TEST_FILE_PATH).unwrap();
+        let encoded = client.to_base64().unwrap();
+        assert!(encoded.starts_with("SQRLDATA"));
+        assert_eq!(
+            SqrlClient::from_base64(&encoded)
+                .unwrap()
+                .to_binary()
+                .unwrap(),
+            client.to_binary().unwrap()
+        );
+    }
+
+    #[test]
+    fn base64_rejects_bad_header() {
+        assert!(SqrlClient::from_base64("").is_err());
+        assert!(SqrlClient::from_base64("SQRL").is_err());
+        assert!(SqrlClient::from_base64("SQRLDAT\u{e9}").is_err());
+        assert!(SqrlClient::from_base64("\u{1F511}\u{1F511}\u{1F511}").is_err());
+        assert!(SqrlClient::from_base64("sqrldataAAAA").is_err());
+    }
+
     #[test]
     fn try_textual_identity_loading() {
         let mut client = SqrlClient::from_textual_identity_format(
@@ -881,4 +1629,760 @@ mod tests {
             "Textual identity format not correct!"
         );
     }
+
+    #[test]
+    fn unlocked_identity_matches_client() {
+        let client = SqrlClient::from_file(TEST_FILE_PATH).unwrap();
+        let unlocked = client.unlock(TEST_FILE_PASSWORD).unwrap();
+
+        assert_eq!(
+            unlocked.get_public_identity(TEST_URL, None).unwrap(),
+            client
+                .get_public_identity(TEST_FILE_PASSWORD, TEST_URL, None)
+                .unwrap()
+        );
+        assert_eq!(
+            unlocked
+                .get_secret_index_key(TEST_URL, None, "secret")
+                .unwrap(),
+            client
+                .get_secret_index_key(TEST_FILE_PASSWORD, TEST_URL, None, "secret")
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn unlock_after_rekey() {
+        let mut client = SqrlClient::from_file(TEST_FILE_PATH).unwrap();
+        let original = client.unlock(TEST_FILE_PASSWORD).unwrap();
+        client
+            .rekey_identity(TEST_FILE_PASSWORD, TEST_FILE_RESCUE_CODE)
+            .unwrap();
+
+        let rekeyed = client.unlock(TEST_FILE_PASSWORD).unwrap();
+        assert_ne!(
+            rekeyed.get_public_identity(TEST_URL, None).unwrap(),
+            original.get_public_identity(TEST_URL, None).unwrap()
+        );
+    }
+
+    #[test]
+    fn rekeyed_identity_signs_with_previous_identity() {
+        let mut client = SqrlClient::from_file(TEST_FILE_PATH).unwrap();
+        let original = client.unlock(TEST_FILE_PASSWORD).unwrap();
+        client
+            .rekey_identity(TEST_FILE_PASSWORD, TEST_FILE_RESCUE_CODE)
+            .unwrap();
+        let rekeyed = client.unlock(TEST_FILE_PASSWORD).unwrap();
+
+        let params = sqrl_protocol::client_request::ClientParameters::new(
+            sqrl_protocol::client_request::ClientCommand::Query,
+            rekeyed.get_public_identity(TEST_URL, None).unwrap(),
+        );
+        let server = sqrl_protocol::client_request::ServerData::Url {
+            url: parse_url(TEST_URL).unwrap(),
+        };
+        let mut request = ClientRequest::new(
+            params,
+            server,
+            ed25519_dalek::Signature::from_bytes(&[0; 64]),
+        );
+        rekeyed
+            .sign_request(TEST_URL, None, &mut request, None)
+            .unwrap();
+
+        // The pidk has to be the identity the site knows the user by from before the rekey
+        assert_eq!(
+            request.client_params.previous_identity_key,
+            Some(original.get_public_identity(TEST_URL, None).unwrap())
+        );
+        let signature = request.previous_identity_signature.unwrap();
+        assert!(request
+            .client_params
+            .previous_identity_key
+            .unwrap()
+            .verify_strict(common::signed_string(&request).as_bytes(), &signature)
+            .is_ok());
+    }
+
+    #[test]
+    fn cancelled_rekey_leaves_identity_unchanged() {
+        let mut client = SqrlClient::from_file(TEST_FILE_PATH).unwrap();
+
+        // Let the password and rescue code be decrypted, then cancel creating the new identity
+        let mut progress = |progress: EnScryptProgress| match progress {
+            EnScryptProgress::Iterations { .. } => ProgressAction::Continue,
+            EnScryptProgress::Time { .. } => ProgressAction::Cancel,
+        };
+        let result = client.rekey_identity_with_options(
+            TEST_FILE_PASSWORD,
+            TEST_FILE_RESCUE_CODE,
+            OperationOptions {
+                progress: Some(&mut progress),
+                ..Default::default()
+            },
+        );
+
+        assert_eq!(result.unwrap_err().kind(), &SqrlErrorKind::Cancelled);
+        assert_eq!(client, SqrlClient::from_file(TEST_FILE_PATH).unwrap());
+    }
+
+    #[test]
+    fn cancelled_settings_update_leaves_identity_unchanged() {
+        let mut client = SqrlClient::from_file(TEST_FILE_PATH).unwrap();
+
+        // Let the password be decrypted, then cancel when EnScrypt starts
+        // again to re-encrypt with the new settings
+        let mut runs = 0;
+        let settings = SettingsUpdate {
+            hint_length: Some(6),
+            ..Default::default()
+        };
+        let mut progress = |progress: EnScryptProgress| {
+            if let EnScryptProgress::Iterations { done: 0, .. } = progress {
+                runs += 1;
+            }
+            match runs {
+                1 => ProgressAction::Continue,
+                _ => ProgressAction::Cancel,
+            }
+        };
+        let result = client.update_config_settings_with_options(
+            TEST_FILE_PASSWORD,
+            settings,
+            OperationOptions {
+                progress: Some(&mut progress),
+                ..Default::default()
+            },
+        );
+
+        assert_eq!(result.unwrap_err().kind(), &SqrlErrorKind::Cancelled);
+        assert_eq!(client, SqrlClient::from_file(TEST_FILE_PATH).unwrap());
+    }
+
+    #[test]
+    fn quick_pass_unlocks_with_hint() {
+        let (mut client, _) = SqrlClient::new("password").unwrap();
+        client
+            .update_config_settings("password", None, Some(4), None, None)
+            .unwrap();
+        assert!(!client.has_quick_pass());
+
+        let unlocked = client.enable_quick_pass("password").unwrap();
+        assert!(client.has_quick_pass());
+
+        let quick = client.unlock_with_hint("pass").unwrap();
+        assert_eq!(
+            quick.get_public_identity(TEST_URL, None).unwrap(),
+            unlocked.get_public_identity(TEST_URL, None).unwrap()
+        );
+
+        // A wrong hint wipes the cached keys
+        assert_eq!(
+            client.unlock_with_hint("fail").unwrap_err().kind(),
+            &SqrlErrorKind::WrongPassword
+        );
+        assert!(!client.has_quick_pass());
+        assert_eq!(
+            client.unlock_with_hint("pass").unwrap_err().kind(),
+            &SqrlErrorKind::QuickPassUnavailable
+        );
+    }
+
+    #[test]
+    fn cancelled_hint_unlock_keeps_quick_pass() {
+        let (mut client, _) = test_client();
+        client
+            .update_config_settings("password", None, Some(4), None, None)
+            .unwrap();
+        client.enable_quick_pass("password").unwrap();
+
+        let mut cancel = |_: EnScryptProgress| ProgressAction::Cancel;
+        let result = client.unlock_with_hint_with_progress("pass", &mut cancel);
+        assert_eq!(result.unwrap_err().kind(), &SqrlErrorKind::Cancelled);
+        assert!(client.has_quick_pass());
+
+        let mut updates = 0;
+        let mut count = |_: EnScryptProgress| {
+            updates += 1;
+            ProgressAction::Continue
+        };
+        assert!(client
+            .unlock_with_hint_with_progress("pass", &mut count)
+            .is_ok());
+        assert!(updates > 0);
+    }
+
+    #[test]
+    fn cancelled_rekey_keeps_quick_pass() {
+        let (mut client, rescue_code) = test_client();
+        client
+            .update_config_settings("password", None, Some(4), None, None)
+            .unwrap();
+        client.enable_quick_pass("password").unwrap();
+
+        // Let the password and rescue code be decrypted, then cancel creating the new identity
+        let mut runs = 0;
+        let mut progress = |progress: EnScryptProgress| {
+            if let EnScryptProgress::Iterations { done: 0, .. } = progress {
+                runs += 1;
+            }
+            match runs {
+                1 | 2 => ProgressAction::Continue,
+                _ => ProgressAction::Cancel,
+            }
+        };
+        let result = client.rekey_identity_with_options(
+            "password",
+            &rescue_code,
+            OperationOptions {
+                progress: Some(&mut progress),
+                ..Default::default()
+            },
+        );
+
+        assert_eq!(result.unwrap_err().kind(), &SqrlErrorKind::Cancelled);
+        assert!(client.has_quick_pass());
+        assert!(client.unlock_with_hint("pass").is_ok());
+    }
+
+    #[test]
+    fn cancelled_settings_update_keeps_quick_pass() {
+        let (mut client, _) = test_client();
+        client
+            .update_config_settings("password", None, Some(4), None, None)
+            .unwrap();
+        client.enable_quick_pass("password").unwrap();
+
+        // Let the password be decrypted, then cancel re-encrypting with the new settings
+        let mut runs = 0;
+        let mut progress = |progress: EnScryptProgress| {
+            if let EnScryptProgress::Iterations { done: 0, .. } = progress {
+                runs += 1;
+            }
+            match runs {
+                1 => ProgressAction::Continue,
+                _ => ProgressAction::Cancel,
+            }
+        };
+        let result = client.update_config_settings_with_options(
+            "password",
+            SettingsUpdate {
+                idle_timeout_min: Some(10),
+                ..Default::default()
+            },
+            OperationOptions {
+                progress: Some(&mut progress),
+                ..Default::default()
+            },
+        );
+
+        assert_eq!(result.unwrap_err().kind(), &SqrlErrorKind::Cancelled);
+        assert!(client.has_quick_pass());
+        assert!(client.unlock_with_hint("pass").is_ok());
+
+        // A successful update still clears it
+        client
+            .update_config_settings("password", None, None, None, Some(10))
+            .unwrap();
+        assert!(!client.has_quick_pass());
+    }
+
+    #[test]
+    fn quick_pass_uses_provided_rng() {
+        // Counts the random bytes asked for
+        struct CountingRng(StdRng, usize);
+
+        impl RngCore for CountingRng {
+            fn next_u32(&mut self) -> u32 {
+                self.1 += 4;
+                self.0.next_u32()
+            }
+
+            fn next_u64(&mut self) -> u64 {
+                self.1 += 8;
+                self.0.next_u64()
+            }
+
+            fn fill_bytes(&mut self, dest: &mut [u8]) {
+                self.1 += dest.len();
+                self.0.fill_bytes(dest)
+            }
+
+            fn try_fill_bytes(&mut self, dest: &mut [u8]) -> result::Result<(), rand::Error> {
+                self.1 += dest.len();
+                self.0.try_fill_bytes(dest)
+            }
+        }
+
+        impl CryptoRng for CountingRng {}
+
+        let (mut client, _) = test_client();
+        let mut rng = CountingRng(StdRng::seed_from_u64(42), 0);
+        client
+            .enable_quick_pass_with_options(
+                "password",
+                OperationOptions {
+                    rng: Some(&mut rng),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        assert_eq!(rng.1, 0, "Nothing to encrypt without a hint length");
+
+        client
+            .update_config_settings("password", None, Some(4), None, None)
+            .unwrap();
+        client
+            .enable_quick_pass_with_options(
+                "password",
+                OperationOptions {
+                    rng: Some(&mut rng),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        assert!(rng.1 > 0);
+        assert!(client.unlock_with_hint("pass").is_ok());
+    }
+
+    #[test]
+    fn expired_quick_pass_is_wiped() {
+        let (mut client, _) = test_client();
+        client
+            .update_config_settings("password", None, Some(4), None, Some(5))
+            .unwrap();
+        client.enable_quick_pass("password").unwrap();
+        assert!(client.has_quick_pass());
+        client.quick_pass.as_mut().unwrap().expire();
+
+        assert_eq!(
+            client.unlock_with_hint("pass").unwrap_err().kind(),
+            &SqrlErrorKind::QuickPassUnavailable
+        );
+        assert!(!client.has_quick_pass());
+        assert!(client.quick_pass.is_none());
+    }
+
+    #[test]
+    fn load_then_write_unknown_block() {
+        let mut data = std::fs::read(TEST_FILE_PATH).unwrap();
+        // Add a block with type 0x1234 and four bytes of data
+        data.extend_from_slice(&[8, 0, 0x34, 0x12, 1, 2, 3, 4]);
+
+        let client = SqrlClient::read_from(data.as_slice()).unwrap();
+        assert_eq!(client.unknown_blocks.len(), 1);
+        assert_eq!(client.to_binary().unwrap(), data);
+    }
+
+    #[test]
+    fn load_then_write_unknown_block_in_middle() {
+        let mut data = std::fs::read(TEST_FILE_PATH).unwrap();
+        // Add a block with type 0x1234 between the password and rescue code blocks
+        let password_block_end = FILE_HEADER.len() + 125;
+        data.splice(
+            password_block_end..password_block_end,
+            [8, 0, 0x34, 0x12, 1, 2, 3, 4],
+        );
+
+        let client = SqrlClient::read_from(data.as_slice()).unwrap();
+        assert_eq!(client.unknown_blocks.len(), 1);
+        assert_eq!(client.to_binary().unwrap(), data);
+    }
+
+    #[test]
+    fn write_to_then_read_from() {
+        let client = SqrlClient::from_file(TEST_FILE_PATH).unwrap();
+        let mut buffer = Vec::new();
+        client.write_to(&mut buffer).unwrap();
+        assert_eq!(buffer, std::fs::read(TEST_FILE_PATH).unwrap());
+
+        let read_client = SqrlClient::read_from(buffer.as_slice()).unwrap();
+        assert_eq!(client, read_client);
+    }
+
+    #[test]
+    fn read_from_truncated_data_fails() {
+        let data = std::fs::read(TEST_FILE_PATH).unwrap();
+        assert!(SqrlClient::read_from(&data[..data.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn wrong_password_error_kind() {
+        let client = SqrlClient::from_file(TEST_FILE_PATH).unwrap();
+        let error = client.unlock("not the password").err().unwrap();
+        assert_eq!(error.kind(), &SqrlErrorKind::WrongPassword);
+    }
+
+    #[test]
+    fn wrong_rescue_code_error_kind() {
+        let client = SqrlClient::from_file(TEST_FILE_PATH).unwrap();
+        let error = client
+            .recreate_from_rescue_code("0000-0000-0000-0000-0000-0000", "password")
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), &SqrlErrorKind::WrongRescueCode);
+    }
+
+    #[test]
+    fn corrupt_data_error_kind() {
+        let data = std::fs::read(TEST_FILE_PATH).unwrap();
+        let error = SqrlClient::read_from(&data[..data.len() - 1])
+            .err()
+            .unwrap();
+        assert!(matches!(error.kind(), SqrlErrorKind::CorruptData { .. }));
+
+        let error = SqrlClient::read_from(&b"sqrldatx"[..]).err().unwrap();
+        assert_eq!(error.kind(), &SqrlErrorKind::CorruptData { offset: 0 });
+    }
+
+    #[test]
+    fn io_error_kind() {
+        // Fails after part of the first block has been read
+        struct FailingReader;
+        impl Read for FailingReader {
+            fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+                Err(std::io::Error::other("read failed"))
+            }
+        }
+
+        let data = std::fs::read(TEST_FILE_PATH).unwrap();
+        let error = SqrlClient::read_from(data[..20].chain(FailingReader))
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), &SqrlErrorKind::Io);
+
+        let error = SqrlClient::read_from(FailingReader).err().unwrap();
+        assert_eq!(error.kind(), &SqrlErrorKind::Io);
+    }
+
+    #[test]
+    fn invalid_textual_identity_error_kind() {
+        let invalid = TEST_FILE_TEXTUAL_IDENTITY.replace("vMsZ", "vMsY");
+        let error = SqrlClient::from_textual_identity_format(
+            &invalid,
+            TEST_FILE_RESCUE_CODE,
+            TEST_FILE_PASSWORD,
+        )
+        .err()
+        .unwrap();
+        assert_eq!(
+            error.kind(),
+            &SqrlErrorKind::InvalidTextualIdentity { line: 2 }
+        );
+    }
+
+    #[test]
+    fn textual_identity_with_multibyte_characters() {
+        // A multibyte check character at the end of a line
+        let mut lines: Vec<String> = TEST_FILE_TEXTUAL_IDENTITY
+            .lines()
+            .map(str::to_owned)
+            .collect();
+        lines[1].pop();
+        lines[1].push('é');
+        let error = SqrlClient::from_textual_identity_format(
+            &lines.join("\n"),
+            TEST_FILE_RESCUE_CODE,
+            TEST_FILE_PASSWORD,
+        )
+        .err()
+        .unwrap();
+        assert_eq!(
+            error.kind(),
+            &SqrlErrorKind::InvalidTextualIdentity { line: 1 }
+        );
+
+        // A non-ASCII character in the middle of a line
+        let invalid = TEST_FILE_TEXTUAL_IDENTITY.replacen("vMsZ", "vMéZ", 1);
+        let error = SqrlClient::from_textual_identity_format(
+            &invalid,
+            TEST_FILE_RESCUE_CODE,
+            TEST_FILE_PASSWORD,
+        )
+        .err()
+        .unwrap();
+        assert_eq!(
+            error.kind(),
+            &SqrlErrorKind::InvalidTextualIdentity { line: 2 }
+        );
+    }
+
+    #[test]
+    fn invalid_url_error_kind() {
+        let error = parse_url("https://sqrl.grc.com").err().unwrap();
+        assert_eq!(error.kind(), &SqrlErrorKind::InvalidUrl);
+    }
+
+    #[test]
+    fn new_with_rng_is_deterministic() {
+        let new = |rng: &mut StdRng| {
+            SqrlClient::new_with_options(
+                "password",
+                OperationOptions {
+                    rng: Some(rng),
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+        };
+        let (client, rescue_code) = new(&mut StdRng::seed_from_u64(42));
+        let (second_client, second_rescue_code) = new(&mut StdRng::seed_from_u64(42));
+
+        assert_eq!(rescue_code, second_rescue_code);
+        assert_eq!(
+            client
+                .get_public_identity("password", TEST_URL, None)
+                .unwrap(),
+            second_client
+                .get_public_identity("password", TEST_URL, None)
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn recreate_with_rng_is_deterministic() {
+        let (client, rescue_code) = test_client();
+        let recreate = |rng: &mut StdRng| {
+            client
+                .recreate_from_rescue_code_with_options(
+                    &rescue_code,
+                    "new_password",
+                    OperationOptions {
+                        rng: Some(rng),
+                        ..Default::default()
+                    },
+                )
+                .unwrap()
+        };
+
+        assert_eq!(
+            recreate(&mut StdRng::seed_from_u64(42)),
+            recreate(&mut StdRng::seed_from_u64(42))
+        );
+    }
+
+    #[test]
+    fn rekey_with_options() {
+        let (client, rescue_code) = test_client();
+        let mut reports = 0;
+        let mut rekey = |rng: &mut StdRng| {
+            let mut progress = |_| {
+                reports += 1;
+                ProgressAction::Continue
+            };
+            client
+                .clone()
+                .rekey_identity_with_options(
+                    "password",
+                    &rescue_code,
+                    OperationOptions {
+                        rng: Some(rng),
+                        progress: Some(&mut progress),
+                        ..Default::default()
+                    },
+                )
+                .unwrap()
+        };
+
+        let new_rescue_code = rekey(&mut StdRng::seed_from_u64(42));
+        assert_eq!(new_rescue_code, rekey(&mut StdRng::seed_from_u64(42)));
+        assert!(reports > 0);
+    }
+
+    #[test]
+    fn unlock_with_rescue_code_matches_password() {
+        let (mut client, rescue_code) = test_client();
+        let rescue_code = client.rekey_identity("password", &rescue_code).unwrap();
+
+        let unlocked = client.unlock("password").unwrap();
+        let rescued = client.unlock_with_rescue_code(&rescue_code).unwrap();
+        assert_eq!(
+            unlocked.get_public_identity(TEST_URL, None).unwrap(),
+            rescued.get_public_identity(TEST_URL, None).unwrap()
+        );
+
+        // Only the rescued identity can sign unlock requests for the current identity
+        let server_unlock_key = [7; 32];
+        let error = unlocked
+            .generate_unlock_request_signing_key(server_unlock_key, None)
+            .unwrap_err();
+        assert_eq!(error.kind(), &SqrlErrorKind::RescueCodeRequired);
+        assert_eq!(
+            rescued
+                .generate_unlock_request_signing_key(server_unlock_key, None)
+                .unwrap(),
+            client
+                .generate_unlock_request_signing_key(&rescue_code, server_unlock_key)
+                .unwrap()
+        );
+        assert_eq!(
+            unlocked
+                .generate_unlock_request_signing_key(server_unlock_key, Some(0))
+                .unwrap(),
+            rescued
+                .generate_unlock_request_signing_key(server_unlock_key, Some(0))
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn new_with_policy_uses_iteration_count() {
+        let (mut client, rescue_code) = SqrlClient::new_with_options(
+            "password",
+            policy_options(ScryptPolicy::with_iterations(2)),
+        )
+        .unwrap();
+        client.verify_password("password").unwrap();
+        client.rekey_identity("password", &rescue_code).unwrap();
+
+        let minimum = ScryptMinimum {
+            log_n_factor: 9,
+            password_iterations: 3,
+            rescue_code_iterations: 2,
+        };
+        assert_eq!(
+            client.check_scrypt_parameters(&minimum),
+            vec![ScryptWarning::IterationsTooLow {
+                block: ScryptBlock::Password,
+                iterations: 2,
+                minimum: 3,
+            }]
+        );
+    }
+
+    #[test]
+    fn textual_identity_with_policy() {
+        let policy = ScryptPolicy::with_iterations(2);
+        let mut client = SqrlClient::from_textual_identity_format_with_options(
+            TEST_FILE_TEXTUAL_IDENTITY,
+            TEST_FILE_RESCUE_CODE,
+            "new_password",
+            policy_options(policy.clone()),
+        )
+        .unwrap();
+        client.verify_password("new_password").unwrap();
+        assert_eq!(client.scrypt_policy(), &policy);
+        assert_eq!(
+            client.user_configuration.scrypt_config().iteration_factor,
+            Some(2)
+        );
+    }
+
+    #[test]
+    fn read_from_keeps_stored_log_n_factor() {
+        let policy = ScryptPolicy {
+            log_n_factor: 10,
+            ..ScryptPolicy::with_iterations(1)
+        };
+        let (client, _) = SqrlClient::new_with_options("password", policy_options(policy)).unwrap();
+        let mut data = Vec::new();
+        client.write_to(&mut data).unwrap();
+
+        let read = SqrlClient::read_from(data.as_slice()).unwrap();
+        assert_eq!(read.scrypt_policy().log_n_factor, 10);
+        assert_eq!(read.scrypt_policy().iteration_count, None);
+    }
+
+    #[test]
+    fn rekey_uses_current_policy() {
+        let (mut client, rescue_code) = test_client();
+        let old_salt = client.user_configuration.scrypt_config().random_salt;
+        client
+            .set_scrypt_policy(ScryptPolicy {
+                log_n_factor: 10,
+                ..ScryptPolicy::with_iterations(2)
+            })
+            .unwrap();
+
+        client.rekey_identity("password", &rescue_code).unwrap();
+        let password_scrypt = client.user_configuration.scrypt_config();
+        assert_eq!(password_scrypt.log_n_factor, 10);
+        assert_eq!(password_scrypt.iteration_factor, Some(2));
+        assert_ne!(password_scrypt.random_salt, old_salt);
+        let rescue_code_scrypt = client.identity_unlock.scrypt_config();
+        assert_eq!(rescue_code_scrypt.log_n_factor, 10);
+        assert_eq!(rescue_code_scrypt.iteration_factor, Some(2));
+        client.verify_password("password").unwrap();
+    }
+
+    #[test]
+    fn rekey_uses_current_policy_time() {
+        let (mut client, rescue_code) = test_client();
+        client
+            .set_scrypt_policy(ScryptPolicy {
+                password_time_sec: 1,
+                rescue_code_time_sec: 1,
+                ..Default::default()
+            })
+            .unwrap();
+
+        client.rekey_identity("password", &rescue_code).unwrap();
+        assert_eq!(client.user_configuration.pw_verify_sec(), 1);
+        let iterations = client
+            .user_configuration
+            .scrypt_config()
+            .iteration_factor
+            .unwrap();
+        assert!(iterations >= 1);
+        client.verify_password("password").unwrap();
+    }
+
+    #[test]
+    fn new_with_policy_rejects_zero_iterations() {
+        assert!(SqrlClient::new_with_options(
+            "password",
+            policy_options(ScryptPolicy::with_iterations(0))
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn new_with_policy_rejects_zero_time_budgets() {
+        for policy in [
+            ScryptPolicy {
+                password_time_sec: 0,
+                ..Default::default()
+            },
+            ScryptPolicy {
+                rescue_code_time_sec: 0,
+                ..Default::default()
+            },
+        ] {
+            assert!(SqrlClient::new_with_options("password", policy_options(policy)).is_err());
+        }
+
+        // A fixed iteration count doesn't use the time budgets
+        let policy = ScryptPolicy {
+            password_time_sec: 0,
+            rescue_code_time_sec: 0,
+            ..ScryptPolicy::with_iterations(1)
+        };
+        assert!(SqrlClient::new_with_options("password", policy_options(policy)).is_ok());
+    }
+
+    #[test]
+    fn load_with_minimum_warns() {
+        let minimum = ScryptMinimum {
+            log_n_factor: 10,
+            password_iterations: 1,
+            rescue_code_iterations: 1,
+        };
+        let (_, warnings) = SqrlClient::from_file_with_minimum(TEST_FILE_PATH, &minimum).unwrap();
+        assert_eq!(
+            warnings,
+            vec![
+                ScryptWarning::LogNFactorTooLow {
+                    block: ScryptBlock::Password,
+                    log_n_factor: 9,
+                    minimum: 10,
+                },
+                ScryptWarning::LogNFactorTooLow {
+                    block: ScryptBlock::RescueCode,
+                    log_n_factor: 9,
+                    minimum: 10,
+                },
+            ]
+        );
+    }
 }
@@ -0,0 +1,1312 @@
+//! Drive a full SQRL login (a query followed by an ident) for an unlocked identity
+
+use crate::{
+    ask::{Ask, AskAnswer, AskHandler},
+    common::{encode_request, signed_string},
+    error::{SqrlError, SqrlErrorKind},
+    parse_url,
+    security::{SecurityHandler, SecurityWarning, WarningAction},
+    transport::SqrlTransport,
+    ConfigOptions, Result, UnlockedIdentity,
+};
+use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine};
+use ed25519_dalek::{Signature, Signer, SigningKey};
+use sqrl_protocol::{
+    client_request::{ClientCommand, ClientOption, ClientParameters, ClientRequest, ServerData},
+    server_response::{ServerResponse, TIFValue},
+};
+use url::Url;
+
+/// The result of a finished login
+#[derive(Clone, Debug, PartialEq)]
+pub enum LoginOutcome {
+    /// The server knew the current identity and logged the user in
+    LoggedIn {
+        /// The url the server wants the browser sent to, if any
+        success_url: Option<String>,
+    },
+    /// The server did not know the identity, and it has now been associated
+    /// with a new account
+    IdentityAssociated {
+        /// The url the server wants the browser sent to, if any
+        success_url: Option<String>,
+    },
+    /// The server knew a previous identity, and has updated the account to
+    /// the current identity
+    PreviousIdentityUpdated {
+        /// The url the server wants the browser sent to, if any
+        success_url: Option<String>,
+        /// Which previous identity the server knew (0 is the most recent)
+        previous_identity_index: usize,
+    },
+    /// The server had disabled SQRL logins for the identity, and has now
+    /// enabled them again
+    IdentityEnabled {
+        /// The url the server wants the browser sent to, if any
+        success_url: Option<String>,
+    },
+    /// The server did not log the user in
+    Failed(LoginFailure),
+}
+
+/// Why the server did not log the user in
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LoginFailure {
+    /// SQRL logins have been disabled for this identity
+    SqrlDisabled,
+    /// The server does not support the requested function
+    FunctionNotSupported,
+    /// The server had a temporary problem, and the login can be retried
+    TransientError,
+    /// The server could not complete the command
+    CommandFailed,
+    /// The server thinks the client sent an invalid request
+    ClientFailure,
+    /// The server did not accept the identity key
+    BadId,
+    /// The identity has been replaced by a newer identity
+    IdentitySuperseded,
+}
+
+// What the server said about the identity in its query response
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum IdentityMatch {
+    Current,
+    Previous,
+    Unknown,
+    // The current identity matched, but has been disabled
+    Disabled,
+}
+
+#[derive(Debug)]
+enum LoginState {
+    // A repeated query (trying another previous identity, or asking for the
+    // suk) echoes the last server response, exactly as the server encoded it
+    SendQuery {
+        last_response: Option<String>,
+        request_suk: bool,
+    },
+    AwaitQuery {
+        requested_suk: bool,
+    },
+    SendIdent {
+        response: Box<ServerResponse>,
+        // The ident echoes the response exactly as the server encoded it
+        original_response: String,
+        identity_match: IdentityMatch,
+    },
+    AwaitIdent {
+        identity_match: IdentityMatch,
+    },
+    Finished,
+}
+
+/// A single login to a SQRL server
+///
+/// The session produces each signed [`ClientRequest`] in turn, starting with
+/// the query. Each request should be encoded with
+/// [`crate::common::encode_request`] and sent to [`LoginSession::post_url`],
+/// and the server's response passed to [`LoginSession::handle_response`],
+/// until an outcome is returned.
+#[derive(Debug)]
+pub struct LoginSession {
+    identity: UnlockedIdentity,
+    url: String,
+    alternate_identity: Option<String>,
+    origin: String,
+    post_url: String,
+    previous_key_index: usize,
+    ask: Option<Ask>,
+    button: Option<u8>,
+    client_provided_session: bool,
+    options: Option<Vec<ClientOption>>,
+    same_device: bool,
+    warning: Option<SecurityWarning>,
+    // The user chose to continue despite a possible man-in-the-middle
+    mitm_accepted: bool,
+    state: LoginState,
+}
+
+impl LoginSession {
+    /// Start a login to the sqrl:// url, using the unlocked identity
+    pub fn new(
+        identity: &UnlockedIdentity,
+        url: &str,
+        alternate_identity: Option<&str>,
+    ) -> Result<Self> {
+        // Make sure this is a valid SQRL url before going any further
+        parse_url(url)?;
+
+        let parsed = Url::parse(url)?;
+        let host = parsed.host_str().ok_or(SqrlError::with_kind(
+            SqrlErrorKind::InvalidUrl,
+            "The SQRL url does not have a host".to_owned(),
+        ))?;
+        let origin = match parsed.port() {
+            Some(port) => format!("https://{}:{}", host, port),
+            None => format!("https://{}", host),
+        };
+        let post_url = match parsed.query() {
+            Some(query) => format!("{}{}?{}", origin, parsed.path(), query),
+            None => format!("{}{}", origin, parsed.path()),
+        };
+
+        Ok(LoginSession {
+            identity: identity.clone(),
+            url: url.to_owned(),
+            alternate_identity: alternate_identity.map(str::to_owned),
+            origin,
+            post_url,
+            previous_key_index: 0,
+            ask: None,
+            button: None,
+            client_provided_session: false,
+            options: None,
+            same_device: false,
+            warning: None,
+            mitm_accepted: false,
+            state: LoginState::SendQuery {
+                last_response: None,
+                request_suk: false,
+            },
+        })
+    }
+
+    /// The https url the next request needs to be sent to
+    pub fn post_url(&self) -> &str {
+        &self.post_url
+    }
+
+    /// Ask the server to return the success url with the ident response
+    /// (opt=cps), so the client can send the browser there itself
+    pub fn set_client_provided_session(&mut self, client_provided_session: bool) {
+        self.client_provided_session = client_provided_session;
+    }
+
+    /// Send these opt= values instead of the ones from the user's stored
+    /// settings
+    ///
+    /// The suk and cps options are still added when the login needs them.
+    pub fn set_options(&mut self, options: Vec<ClientOption>) {
+        self.options = Some(options);
+    }
+
+    /// Mark the login as started on this device (such as by clicking a
+    /// sqrl:// link), rather than by scanning a QR code
+    ///
+    /// If the user has [`ConfigOptions::WarnManInTheMiddle`] set, a same
+    /// device login warns when the server says the IPs didn't match.
+    pub fn set_same_device(&mut self, same_device: bool) {
+        self.same_device = same_device;
+    }
+
+    /// Returns true once the login has an outcome
+    pub fn is_finished(&self) -> bool {
+        matches!(self.state, LoginState::Finished)
+    }
+
+    /// The question the server has asked, which needs to be answered with
+    /// [`LoginSession::answer_ask`] before the next request can be sent
+    pub fn ask(&self) -> Option<&Ask> {
+        self.ask.as_ref()
+    }
+
+    /// Answer the server's question, sending the chosen button with the next request
+    pub fn answer_ask(&mut self, answer: AskAnswer) -> Result<()> {
+        let ask = self.ask.as_ref().ok_or(SqrlError::new(
+            "The server has not asked a question".to_owned(),
+        ))?;
+        let button = ask.button_value(answer);
+        if answer == AskAnswer::Cancel {
+            self.state = LoginState::Finished;
+        }
+
+        self.button = button?;
+        self.ask = None;
+        Ok(())
+    }
+
+    /// The security warning raised by the last response, which needs to be
+    /// answered with [`LoginSession::answer_warning`] before the next request
+    /// can be sent
+    pub fn warning(&self) -> Option<SecurityWarning> {
+        self.warning
+    }
+
+    /// Decide whether to continue the login after a security warning
+    pub fn answer_warning(&mut self, action: WarningAction) -> Result<()> {
+        let warning = self.warning.take().ok_or(SqrlError::new(
+            "There is no security warning to answer".to_owned(),
+        ))?;
+
+        match action {
+            WarningAction::Continue => {
+                if warning == SecurityWarning::PossibleMitm {
+                    self.mitm_accepted = true;
+                }
+                Ok(())
+            }
+            WarningAction::Abort => {
+                self.state = LoginState::Finished;
+                Err(SqrlError::with_kind(
+                    SqrlErrorKind::Cancelled,
+                    "The login was aborted after a security warning".to_owned(),
+                ))
+            }
+        }
+    }
+
+    /// Generate the next signed request to send to the server
+    pub fn next_request(&mut self) -> Result<ClientRequest> {
+        if self.warning.is_some() {
+            return Err(SqrlError::new(
+                "The security warning needs to be answered first".to_owned(),
+            ));
+        }
+        if self.ask.is_some() {
+            return Err(SqrlError::new(
+                "The server's question needs to be answered first".to_owned(),
+            ));
+        }
+
+        match std::mem::replace(&mut self.state, LoginState::Finished) {
+            LoginState::SendQuery {
+                last_response,
+                request_suk,
+            } => {
+                let mut params = self.client_parameters(ClientCommand::Query)?;
+                if request_suk {
+                    params
+                        .options
+                        .get_or_insert_with(Vec::new)
+                        .push(ClientOption::ServerUnlockKey);
+                }
+                let server = match last_response {
+                    Some(response) => ServerData::from_base64(&response)?,
+                    None => ServerData::Url {
+                        url: parse_url(&self.url)?,
+                    },
+                };
+                // Queries keep offering the previous identity being tried
+                let request =
+                    self.signed_request(params, server, Some(self.previous_key_index), None)?;
+                self.state = LoginState::AwaitQuery {
+                    requested_suk: request_suk,
+                };
+                Ok(request)
+            }
+            LoginState::SendIdent {
+                response,
+                original_response,
+                identity_match,
+            } => {
+                // Replacing a previous identity or re-enabling this one has to
+                // be signed by the identity's unlock request signing key
+                let unlock_key = match identity_match {
+                    IdentityMatch::Previous => Some(
+                        self.unlock_request_signing_key(&response, Some(self.previous_key_index))?,
+                    ),
+                    IdentityMatch::Disabled => {
+                        Some(self.unlock_request_signing_key(&response, None)?)
+                    }
+                    IdentityMatch::Current | IdentityMatch::Unknown => None,
+                };
+
+                let command = match identity_match {
+                    IdentityMatch::Disabled => ClientCommand::Enable,
+                    _ => ClientCommand::Ident,
+                };
+                let mut params = self.client_parameters(command)?;
+                if matches!(
+                    identity_match,
+                    IdentityMatch::Unknown | IdentityMatch::Previous
+                ) {
+                    // The server needs these to let the rescue code holder unlock the identity later
+                    let keys = self
+                        .identity
+                        .generate_server_unlock_and_verify_unlock_keys()?;
+                    params.server_unlock_key =
+                        Some(BASE64_URL_SAFE_NO_PAD.encode(keys.server_unlock_key.as_bytes()));
+                    params.verify_unlock_key =
+                        Some(BASE64_URL_SAFE_NO_PAD.encode(keys.verify_unlock_key.as_bytes()));
+                }
+
+                let server = ServerData::ServerResponse {
+                    server_response: *response,
+                    original_response,
+                };
+                // Only a previous identity the server knows is sent with the ident
+                let previous_key_index = match identity_match {
+                    IdentityMatch::Previous => Some(self.previous_key_index),
+                    _ => None,
+                };
+                let request =
+                    self.signed_request(params, server, previous_key_index, unlock_key)?;
+                self.state = LoginState::AwaitIdent { identity_match };
+                Ok(request)
+            }
+            state => {
+                self.state = state;
+                Err(SqrlError::new(
+                    "The login is not ready to send a request".to_owned(),
+                ))
+            }
+        }
+    }
+
+    /// Handle the body of the server's response to the last request
+    ///
+    /// Returns the outcome once the login has finished, or None if there is
+    /// another request to send.
+    pub fn handle_response(&mut self, body: &str) -> Result<Option<LoginOutcome>> {
+        let body = body.trim();
+        let response = ServerResponse::from_base64(body)?;
+        self.post_url = match self.resolve_query_url(&response.query_url) {
+            Ok(post_url) => post_url,
+            Err(error) => {
+                // Don't send anything else for this login
+                self.state = LoginState::Finished;
+                return Err(error);
+            }
+        };
+
+        match std::mem::replace(&mut self.state, LoginState::Finished) {
+            LoginState::AwaitQuery { requested_suk } => {
+                // A disabled identity can only be re-enabled by someone with the rescue code
+                let disabled = has_flag(&response, TIFValue::SqrlDisabled)
+                    && has_flag(&response, TIFValue::CurrentIdMatch)
+                    && !has_flag(&response, TIFValue::CommandFailed)
+                    && self.identity.has_identity_unlock_key();
+                if !disabled {
+                    if let Some(failure) = get_failure(&response) {
+                        return Ok(Some(LoginOutcome::Failed(failure)));
+                    }
+                }
+
+                let identity_match = if disabled {
+                    IdentityMatch::Disabled
+                } else if has_flag(&response, TIFValue::CurrentIdMatch) {
+                    IdentityMatch::Current
+                } else if has_flag(&response, TIFValue::PreviousIdMatch) {
+                    IdentityMatch::Previous
+                } else {
+                    IdentityMatch::Unknown
+                };
+
+                // Try the older previous identities before treating this as a new identity
+                if identity_match == IdentityMatch::Unknown
+                    && self.previous_key_index + 1 < self.identity.previous_identity_count()
+                {
+                    self.previous_key_index += 1;
+                    self.state = LoginState::SendQuery {
+                        last_response: Some(body.to_owned()),
+                        request_suk: false,
+                    };
+                    return Ok(None);
+                }
+
+                let needs_suk = matches!(
+                    identity_match,
+                    IdentityMatch::Previous | IdentityMatch::Disabled
+                );
+                if needs_suk && response.server_unlock_key.is_none() {
+                    if requested_suk {
+                        return Err(SqrlError::new(
+                            "The server did not return the server unlock key".to_owned(),
+                        ));
+                    }
+
+                    self.state = LoginState::SendQuery {
+                        last_response: Some(body.to_owned()),
+                        request_suk: true,
+                    };
+                    return Ok(None);
+                }
+
+                // Only the response the ident echoes is checked for a warning
+                // or question, as any earlier one is replaced by a new query.
+                // A same device login should reach the server from the IP the
+                // login page was sent to.
+                if self.same_device
+                    && !self.mitm_accepted
+                    && !has_flag(&response, TIFValue::IpsMatch)
+                    && self
+                        .identity
+                        .option_flags()
+                        .contains(&ConfigOptions::WarnManInTheMiddle)
+                {
+                    self.warning = Some(SecurityWarning::PossibleMitm);
+                }
+
+                if let Some(ask) = &response.ask {
+                    self.ask = Some(Ask::parse(ask)?);
+                    self.button = None;
+                }
+
+                self.state = LoginState::SendIdent {
+                    response: Box::new(response),
+                    original_response: body.to_owned(),
+                    identity_match,
+                };
+                Ok(None)
+            }
+            LoginState::AwaitIdent { identity_match } => {
+                if let Some(failure) = get_failure(&response) {
+                    return Ok(Some(LoginOutcome::Failed(failure)));
+                }
+
+                let success_url = response.success_url;
+                Ok(Some(match identity_match {
+                    IdentityMatch::Current => LoginOutcome::LoggedIn { success_url },
+                    IdentityMatch::Previous => LoginOutcome::PreviousIdentityUpdated {
+                        success_url,
+                        previous_identity_index: self.previous_key_index,
+                    },
+                    IdentityMatch::Unknown => LoginOutcome::IdentityAssociated { success_url },
+                    IdentityMatch::Disabled => LoginOutcome::IdentityEnabled { success_url },
+                }))
+            }
+            state => {
+                self.state = state;
+                Err(SqrlError::new(
+                    "The login is not waiting for a server response".to_owned(),
+                ))
+            }
+        }
+    }
+
+    /// Run the rest of the login, sending each request with the transport
+    ///
+    /// If the server asks the user a question, the login is cancelled. Use
+    /// [`LoginSession::run_with_ask_handler`] to answer it instead.
+    pub fn run(&mut self, transport: &mut dyn SqrlTransport) -> Result<LoginOutcome> {
+        self.run_with_ask_handler(transport, &mut |_: &Ask| AskAnswer::Cancel)
+    }
+
+    /// Run the rest of the login, sending each request with the transport and
+    /// passing any questions the server asks to the handler
+    ///
+    /// Any security warning aborts the login. Use
+    /// [`LoginSession::run_with_handlers`] to let the user decide instead.
+    pub fn run_with_ask_handler(
+        &mut self,
+        transport: &mut dyn SqrlTransport,
+        ask_handler: &mut dyn AskHandler,
+    ) -> Result<LoginOutcome> {
+        self.run_with_handlers(transport, ask_handler, &mut |_: SecurityWarning| {
+            WarningAction::Abort
+        })
+    }
+
+    /// Run the rest of the login, sending each request with the transport,
+    /// passing any questions the server asks to the ask handler, and any
+    /// security warnings to the security handler
+    pub fn run_with_handlers(
+        &mut self,
+        transport: &mut dyn SqrlTransport,
+        ask_handler: &mut dyn AskHandler,
+        security_handler: &mut dyn SecurityHandler,
+    ) -> Result<LoginOutcome> {
+        loop {
+            if let Some(warning) = self.warning {
+                let action = security_handler.on_warning(warning);
+                self.answer_warning(action)?;
+            }
+            if let Some(ask) = &self.ask {
+                let answer = ask_handler.on_ask(ask);
+                self.answer_ask(answer)?;
+            }
+
+            let request = self.next_request()?;
+            let body = transport.post(&self.post_url, &encode_request(&request))?;
+            if let Some(outcome) = self.handle_response(&body)? {
+                return Ok(outcome);
+            }
+        }
+    }
+
+    fn client_parameters(&self, command: ClientCommand) -> Result<ClientParameters> {
+        let identity_key = self
+            .identity
+            .get_public_identity(&self.url, self.alternate_identity.as_deref())?;
+        let mut params = ClientParameters::new(command, identity_key);
+        params.button = self.button;
+        let mut options = match &self.options {
+            Some(options) => options.iter().map(copy_option).collect(),
+            None => self.identity.client_options(),
+        };
+        if self.client_provided_session {
+            options.push(ClientOption::ClientProvidedSession);
+        }
+        params.options = Some(options);
+        Ok(params)
+    }
+
+    fn signed_request(
+        &self,
+        params: ClientParameters,
+        server: ServerData,
+        previous_key_index: Option<usize>,
+        unlock_key: Option<SigningKey>,
+    ) -> Result<ClientRequest> {
+        // The signature is filled in by sign_request
+        let mut request = ClientRequest::new(params, server, Signature::from_bytes(&[0; 64]));
+        self.identity.sign_request_with_previous(
+            &self.url,
+            self.alternate_identity.as_deref(),
+            &mut request,
+            previous_key_index,
+        )?;
+        if let Some(unlock_key) = unlock_key {
+            let signature = unlock_key.sign(signed_string(&request).as_bytes());
+            request.unlock_request_signature =
+                Some(BASE64_URL_SAFE_NO_PAD.encode(signature.to_bytes()));
+        }
+
+        Ok(request)
+    }
+
+    fn unlock_request_signing_key(
+        &self,
+        response: &ServerResponse,
+        previous_key_index: Option<usize>,
+    ) -> Result<SigningKey> {
+        let server_unlock_key = response.server_unlock_key.as_deref().ok_or(SqrlError::new(
+            "The server did not return the server unlock key".to_owned(),
+        ))?;
+        let server_unlock_key = BASE64_URL_SAFE_NO_PAD
+            .decode(server_unlock_key)?
+            .as_slice()
+            .try_into()?;
+        self.identity
+            .generate_unlock_request_signing_key(server_unlock_key, previous_key_index)
+    }
+
+    // The qry value is a path on the server the login started with. Signed
+    // requests must never be sent anywhere else, so an absolute url is only
+    // accepted if it points back at the same origin.
+    fn resolve_query_url(&self, query_url: &str) -> Result<String> {
+        if query_url.starts_with('/') && !query_url.starts_with("//") {
+            return Ok(format!("{}{}", self.origin, query_url));
+        }
+
+        let parsed = Url::parse(query_url)?;
+        if parsed.origin() != Url::parse(&self.origin)?.origin() {
+            return Err(SqrlError::with_kind(
+                SqrlErrorKind::InvalidUrl,
+                format!(
+                    "The server asked for the next request to be sent to {}, which is not {}",
+                    query_url, self.origin
+                ),
+            ));
+        }
+
+        Ok(query_url.to_owned())
+    }
+}
+
+fn has_flag(response: &ServerResponse, flag: TIFValue) -> bool {
+    response.transaction_indication_flags.contains(&flag)
+}
+
+fn get_failure(response: &ServerResponse) -> Option<LoginFailure> {
+    // Check the most specific failures first, as they come with CommandFailed set
+    let failures = [
+        (TIFValue::TransientError, LoginFailure::TransientError),
+        (TIFValue::ClientFailure, LoginFailure::ClientFailure),
+        (TIFValue::BadId, LoginFailure::BadId),
+        (
+            TIFValue::IdentitySuperseded,
+            LoginFailure::IdentitySuperseded,
+        ),
+        (
+            TIFValue::FunctionNotSupported,
+            LoginFailure::FunctionNotSupported,
+        ),
+        (TIFValue::SqrlDisabled, LoginFailure::SqrlDisabled),
+        (TIFValue::CommandFailed, LoginFailure::CommandFailed),
+    ];
+
+    failures
+        .into_iter()
+        .find(|(flag, _)| has_flag(response, *flag))
+        .map(|(_, failure)| failure)
+}
+
+// ClientOption doesn't implement Clone
+fn copy_option(option: &ClientOption) -> ClientOption {
+    match option {
+        ClientOption::NoIPTest => ClientOption::NoIPTest,
+        ClientOption::SQRLOnly => ClientOption::SQRLOnly,
+        ClientOption::Hardlock => ClientOption::Hardlock,
+        ClientOption::ClientProvidedSession => ClientOption::ClientProvidedSession,
+        ClientOption::ServerUnlockKey => ClientOption::ServerUnlockKey,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ask::ScriptedAskHandler,
+        test_util::{test_client, test_identity},
+        transport::MockServer,
+        ConfigOptions,
+    };
+
+    const TEST_URL: &str = "sqrl://example.com/sqrl?nut=first";
+    const TEST_HOST: &str = "example.com";
+
+    fn response(nut: &str, flags: Vec<TIFValue>) -> String {
+        ServerResponse::new(nut.to_owned(), flags, format!("/sqrl?nut={}", nut)).to_base64()
+    }
+
+    // Corrupts the identity signature of every request
+    struct TamperingTransport<'a>(&'a mut MockServer);
+
+    impl SqrlTransport for TamperingTransport<'_> {
+        fn post(&mut self, url: &str, body: &str) -> Result<String> {
+            let (signed, ids) = body.split_once("&ids=").unwrap();
+            let ids = if let Some(rest) = ids.strip_prefix('A') {
+                format!("B{}", rest)
+            } else {
+                format!("A{}", &ids[1..])
+            };
+            self.0.post(url, &format!("{}&ids={}", signed, ids))
+        }
+    }
+
+    #[test]
+    fn login_with_known_identity() {
+        let identity = test_identity();
+        let mut session = LoginSession::new(&identity, TEST_URL, None).unwrap();
+        assert_eq!(session.post_url(), "https://example.com/sqrl?nut=first");
+
+        let query = session.next_request().unwrap();
+        assert_eq!(query.client_params.command, ClientCommand::Query);
+        assert_eq!(
+            query.client_params.identity_key,
+            identity.get_public_identity(TEST_URL, None).unwrap()
+        );
+        assert!(session.next_request().is_err());
+
+        let outcome = session
+            .handle_response(&response("second", vec![TIFValue::CurrentIdMatch]))
+            .unwrap();
+        assert_eq!(outcome, None);
+        assert_eq!(session.post_url(), "https://example.com/sqrl?nut=second");
+
+        let ident = session.next_request().unwrap();
+        assert_eq!(ident.client_params.command, ClientCommand::Ident);
+        assert_eq!(ident.client_params.server_unlock_key, None);
+        match ident.server_data {
+            ServerData::ServerResponse {
+                server_response, ..
+            } => {
+                assert_eq!(server_response.nut, "second")
+            }
+            _ => panic!("The ident should echo the server response"),
+        }
+
+        let mut ident_response = ServerResponse::new(
+            "third".to_owned(),
+            vec![TIFValue::CurrentIdMatch],
+            "/sqrl".to_owned(),
+        );
+        ident_response.success_url = Some("https://example.com/welcome".to_owned());
+        assert_eq!(
+            session
+                .handle_response(&ident_response.to_base64())
+                .unwrap(),
+            Some(LoginOutcome::LoggedIn {
+                success_url: Some("https://example.com/welcome".to_owned())
+            })
+        );
+        assert!(session.is_finished());
+    }
+
+    #[test]
+    fn requests_are_signed_as_encoded() {
+        let identity = test_identity();
+        let mut session = LoginSession::new(&identity, TEST_URL, None).unwrap();
+        let body = encode_request(&session.next_request().unwrap());
+
+        let request = ClientRequest::from_query_string(&body).unwrap();
+        let value = |key: &str| {
+            body.split('&')
+                .find_map(|pair| pair.strip_prefix(key))
+                .unwrap()
+        };
+        let signed = format!("{}{}", value("client="), value("server="));
+        request
+            .client_params
+            .identity_key
+            .verify_strict(signed.as_bytes(), &request.identity_signature)
+            .unwrap();
+    }
+
+    #[test]
+    fn login_with_unknown_identity_associates() {
+        let mut session = LoginSession::new(&test_identity(), TEST_URL, None).unwrap();
+        session.next_request().unwrap();
+        session
+            .handle_response(&response("second", vec![]))
+            .unwrap();
+        let ident = session.next_request().unwrap();
+        assert!(ident.client_params.server_unlock_key.is_some());
+        assert!(ident.client_params.verify_unlock_key.is_some());
+
+        assert_eq!(
+            session.handle_response(&response("third", vec![])).unwrap(),
+            Some(LoginOutcome::IdentityAssociated { success_url: None })
+        );
+    }
+
+    #[test]
+    fn login_reports_failure() {
+        let mut session = LoginSession::new(&test_identity(), TEST_URL, None).unwrap();
+        session.next_request().unwrap();
+
+        assert_eq!(
+            session
+                .handle_response(&response(
+                    "second",
+                    vec![TIFValue::CommandFailed, TIFValue::TransientError]
+                ))
+                .unwrap(),
+            Some(LoginOutcome::Failed(LoginFailure::TransientError))
+        );
+        assert!(session.is_finished());
+    }
+
+    #[test]
+    fn query_url_on_another_server_is_rejected() {
+        let mut session = LoginSession::new(&test_identity(), TEST_URL, None).unwrap();
+        session.next_request().unwrap();
+
+        let hostile = ServerResponse::new(
+            "second".to_owned(),
+            vec![TIFValue::CurrentIdMatch],
+            "https://evil.example/sqrl?nut=second".to_owned(),
+        );
+        let error = session.handle_response(&hostile.to_base64()).unwrap_err();
+        assert_eq!(error.kind(), &SqrlErrorKind::InvalidUrl);
+        assert_eq!(session.post_url(), "https://example.com/sqrl?nut=first");
+        assert!(session.is_finished());
+        assert!(session.next_request().is_err());
+
+        // An absolute url back to the same server is still fine
+        let mut session = LoginSession::new(&test_identity(), TEST_URL, None).unwrap();
+        session.next_request().unwrap();
+        let same_origin = ServerResponse::new(
+            "second".to_owned(),
+            vec![TIFValue::CurrentIdMatch],
+            "https://example.com/other?nut=second".to_owned(),
+        );
+        session.handle_response(&same_origin.to_base64()).unwrap();
+        assert_eq!(session.post_url(), "https://example.com/other?nut=second");
+    }
+
+    #[test]
+    fn previous_identity_requests_server_unlock_key() {
+        let (mut client, rescue_code) = test_client();
+        client.rekey_identity("password", &rescue_code).unwrap();
+        let identity = client.unlock("password").unwrap();
+
+        let mut session = LoginSession::new(&identity, TEST_URL, None).unwrap();
+        let query = session.next_request().unwrap();
+        assert!(query.client_params.previous_identity_key.is_some());
+
+        // Without the suk, the query is repeated asking for it
+        session
+            .handle_response(&response("second", vec![TIFValue::PreviousIdMatch]))
+            .unwrap();
+        let query = session.next_request().unwrap();
+        assert_eq!(query.client_params.command, ClientCommand::Query);
+        assert_eq!(
+            query.client_params.options,
+            Some(vec![ClientOption::ServerUnlockKey])
+        );
+
+        let mut query_response = ServerResponse::new(
+            "third".to_owned(),
+            vec![TIFValue::PreviousIdMatch],
+            "/sqrl?nut=third".to_owned(),
+        );
+        query_response.server_unlock_key = Some(BASE64_URL_SAFE_NO_PAD.encode([9; 32]));
+        session
+            .handle_response(&query_response.to_base64())
+            .unwrap();
+
+        let ident = session.next_request().unwrap();
+        assert_eq!(ident.client_params.command, ClientCommand::Ident);
+        assert!(ident.client_params.server_unlock_key.is_some());
+        assert!(ident.client_params.verify_unlock_key.is_some());
+        assert!(ident.unlock_request_signature.is_some());
+    }
+
+    #[test]
+    fn secret_index_is_answered() {
+        let (mut client, rescue_code) = test_client();
+        client.rekey_identity("password", &rescue_code).unwrap();
+        let identity = client.unlock("password").unwrap();
+
+        let mut session = LoginSession::new(&identity, TEST_URL, None).unwrap();
+        session.next_request().unwrap();
+        let mut query_response = ServerResponse::new(
+            "second".to_owned(),
+            vec![TIFValue::PreviousIdMatch],
+            "/sqrl?nut=second".to_owned(),
+        );
+        query_response.secret_index = Some("index".to_owned());
+        session
+            .handle_response(&query_response.to_base64())
+            .unwrap();
+
+        // The server knows the previous identity, so the query asking for
+        // the suk answers for both identities
+        let query = session.next_request().unwrap();
+        assert_eq!(query.client_params.command, ClientCommand::Query);
+        assert_eq!(
+            query.client_params.index_secret,
+            Some(
+                identity
+                    .get_secret_index_key(TEST_URL, None, "index")
+                    .unwrap()
+            )
+        );
+        assert!(query.client_params.previous_index_secret.is_some());
+        assert_ne!(
+            query.client_params.previous_index_secret,
+            query.client_params.index_secret
+        );
+    }
+
+    #[test]
+    fn ask_on_a_retried_query_is_not_shown() {
+        let (mut client, rescue_code) = test_client();
+        let rescue_code = client.rekey_identity("password", &rescue_code).unwrap();
+        client.rekey_identity("password", &rescue_code).unwrap();
+        client
+            .update_config_settings(
+                "password",
+                Some(vec![ConfigOptions::WarnManInTheMiddle]),
+                None,
+                None,
+                None,
+            )
+            .unwrap();
+        let identity = client.unlock("password").unwrap();
+        let ask = format!("{}~", BASE64_URL_SAFE_NO_PAD.encode("Link this account?"));
+
+        let mut session = LoginSession::new(&identity, TEST_URL, None).unwrap();
+        session.set_same_device(true);
+        session.next_request().unwrap();
+        let mut query_response = ServerResponse::new(
+            "second".to_owned(),
+            Vec::new(),
+            "/sqrl?nut=second".to_owned(),
+        );
+        query_response.ask = Some(ask.clone());
+        session
+            .handle_response(&query_response.to_base64())
+            .unwrap();
+
+        // The older previous identity is tried with a new query before anything is asked
+        assert!(session.ask().is_none());
+        assert!(session.warning().is_none());
+        let query = session.next_request().unwrap();
+        assert_eq!(query.client_params.command, ClientCommand::Query);
+        assert!(query.client_params.button.is_none());
+
+        query_response.nut = "third".to_owned();
+        query_response.query_url = "/sqrl?nut=third".to_owned();
+        session
+            .handle_response(&query_response.to_base64())
+            .unwrap();
+        assert!(session.ask().is_some());
+        assert!(session.warning().is_some());
+    }
+
+    #[test]
+    fn options_come_from_stored_settings() {
+        let (mut client, _) = test_client();
+        client
+            .update_config_settings(
+                "password",
+                Some(vec![
+                    ConfigOptions::SqrlOnlyLogin,
+                    ConfigOptions::NoSqrlBypass,
+                ]),
+                None,
+                None,
+                None,
+            )
+            .unwrap();
+        let identity = client.unlock("password").unwrap();
+
+        let mut session = LoginSession::new(&identity, TEST_URL, None).unwrap();
+        session.set_client_provided_session(true);
+        let query = session.next_request().unwrap();
+        assert_eq!(
+            query.client_params.options,
+            Some(vec![
+                ClientOption::SQRLOnly,
+                ClientOption::Hardlock,
+                ClientOption::ClientProvidedSession,
+            ])
+        );
+
+        let mut session = LoginSession::new(&identity, TEST_URL, None).unwrap();
+        session.set_options(vec![]);
+        assert_eq!(session.next_request().unwrap().client_params.options, None);
+
+        // Requests signed directly get the same defaults, unless they set their own
+        let new_request = || {
+            ClientRequest::new(
+                ClientParameters::new(
+                    ClientCommand::Query,
+                    identity.get_public_identity(TEST_URL, None).unwrap(),
+                ),
+                ServerData::Url {
+                    url: parse_url(TEST_URL).unwrap(),
+                },
+                Signature::from_bytes(&[0; 64]),
+            )
+        };
+        let mut request = new_request();
+        identity
+            .sign_request(TEST_URL, None, &mut request, None)
+            .unwrap();
+        assert_eq!(
+            request.client_params.options,
+            Some(vec![ClientOption::SQRLOnly, ClientOption::Hardlock])
+        );
+
+        let mut request = new_request();
+        request.client_params.options = Some(vec![ClientOption::NoIPTest]);
+        identity
+            .sign_request(TEST_URL, None, &mut request, None)
+            .unwrap();
+        assert_eq!(
+            request.client_params.options,
+            Some(vec![ClientOption::NoIPTest])
+        );
+    }
+
+    #[test]
+    fn login_associates_then_logs_in() {
+        let identity = test_identity();
+        let mut server = MockServer::new(TEST_HOST);
+
+        let url = server.login_url();
+        let outcome = LoginSession::new(&identity, &url, None)
+            .unwrap()
+            .run(&mut server)
+            .unwrap();
+        assert_eq!(
+            outcome,
+            LoginOutcome::IdentityAssociated {
+                success_url: Some("https://example.com/account".to_owned())
+            }
+        );
+        let identity_key = identity.get_public_identity(&url, None).unwrap();
+        assert!(server.account(&identity_key).is_some());
+
+        let url = server.login_url();
+        let outcome = LoginSession::new(&identity, &url, None)
+            .unwrap()
+            .run(&mut server)
+            .unwrap();
+        assert_eq!(
+            outcome,
+            LoginOutcome::LoggedIn {
+                success_url: Some("https://example.com/account".to_owned())
+            }
+        );
+    }
+
+    #[test]
+    fn nuts_cannot_be_reused() {
+        let identity = test_identity();
+        let mut server = MockServer::new(TEST_HOST);
+
+        let url = server.login_url();
+        LoginSession::new(&identity, &url, None)
+            .unwrap()
+            .run(&mut server)
+            .unwrap();
+        let outcome = LoginSession::new(&identity, &url, None)
+            .unwrap()
+            .run(&mut server)
+            .unwrap();
+        assert_eq!(outcome, LoginOutcome::Failed(LoginFailure::TransientError));
+    }
+
+    #[test]
+    fn invalid_signature_is_rejected() {
+        let identity = test_identity();
+        let mut server = MockServer::new(TEST_HOST);
+
+        let url = server.login_url();
+        let outcome = LoginSession::new(&identity, &url, None)
+            .unwrap()
+            .run(&mut TamperingTransport(&mut server))
+            .unwrap();
+        assert_eq!(outcome, LoginOutcome::Failed(LoginFailure::ClientFailure));
+    }
+
+    #[test]
+    fn association_stores_unlock_keys() {
+        let (client, rescue_code) = test_client();
+        let identity = client.unlock("password").unwrap();
+        let mut server = MockServer::new(TEST_HOST);
+
+        let url = server.login_url();
+        LoginSession::new(&identity, &url, None)
+            .unwrap()
+            .run(&mut server)
+            .unwrap();
+
+        // The rescue code must be able to produce the key that signs unlock requests
+        let account = server
+            .account(&identity.get_public_identity(&url, None).unwrap())
+            .unwrap();
+        let signing_key = client
+            .generate_unlock_request_signing_key(&rescue_code, account.server_unlock_key)
+            .unwrap();
+        assert_eq!(signing_key.verifying_key(), account.verify_unlock_key);
+    }
+
+    #[test]
+    fn rekeyed_identity_replaces_previous() {
+        let (mut client, rescue_code) = test_client();
+        let mut server = MockServer::new(TEST_HOST);
+
+        let url = server.login_url();
+        let old_identity = client.unlock("password").unwrap();
+        LoginSession::new(&old_identity, &url, None)
+            .unwrap()
+            .run(&mut server)
+            .unwrap();
+
+        let rescue_code = client.rekey_identity("password", &rescue_code).unwrap();
+        let identity = client.unlock("password").unwrap();
+        let url = server.login_url();
+        let outcome = LoginSession::new(&identity, &url, None)
+            .unwrap()
+            .run(&mut server)
+            .unwrap();
+        assert_eq!(
+            outcome,
+            LoginOutcome::PreviousIdentityUpdated {
+                success_url: Some("https://example.com/account".to_owned()),
+                previous_identity_index: 0,
+            }
+        );
+
+        // The account has moved to the new identity, with keys from the new rescue code
+        assert!(server
+            .account(&old_identity.get_public_identity(&url, None).unwrap())
+            .is_none());
+        let account = server
+            .account(&identity.get_public_identity(&url, None).unwrap())
+            .unwrap();
+        let signing_key = client
+            .generate_unlock_request_signing_key(&rescue_code, account.server_unlock_key)
+            .unwrap();
+        assert_eq!(signing_key.verifying_key(), account.verify_unlock_key);
+
+        let url = server.login_url();
+        let outcome = LoginSession::new(&identity, &url, None)
+            .unwrap()
+            .run(&mut server)
+            .unwrap();
+        assert!(matches!(outcome, LoginOutcome::LoggedIn { .. }));
+    }
+
+    #[test]
+    fn disabled_identity_is_enabled_with_rescue_code() {
+        let (client, rescue_code) = test_client();
+        let identity = client.unlock("password").unwrap();
+        let mut server = MockServer::new(TEST_HOST);
+
+        let url = server.login_url();
+        LoginSession::new(&identity, &url, None)
+            .unwrap()
+            .run(&mut server)
+            .unwrap();
+        let identity_key = identity.get_public_identity(&url, None).unwrap();
+        let mut account = server.account(&identity_key).unwrap().clone();
+        account.disabled = true;
+        server.add_account(&identity_key, account);
+
+        let url = server.login_url();
+        let outcome = LoginSession::new(&identity, &url, None)
+            .unwrap()
+            .run(&mut server)
+            .unwrap();
+        assert_eq!(outcome, LoginOutcome::Failed(LoginFailure::SqrlDisabled));
+
+        let rescued = client.unlock_with_rescue_code(&rescue_code).unwrap();
+        let url = server.login_url();
+        let outcome = LoginSession::new(&rescued, &url, None)
+            .unwrap()
+            .run(&mut server)
+            .unwrap();
+        assert!(matches!(outcome, LoginOutcome::IdentityEnabled { .. }));
+        assert!(!server.account(&identity_key).unwrap().disabled);
+
+        let url = server.login_url();
+        let outcome = LoginSession::new(&identity, &url, None)
+            .unwrap()
+            .run(&mut server)
+            .unwrap();
+        assert!(matches!(outcome, LoginOutcome::LoggedIn { .. }));
+    }
+
+    #[test]
+    fn older_previous_identities_are_tried() {
+        let (mut client, rescue_code) = test_client();
+        let mut server = MockServer::new(TEST_HOST);
+
+        let url = server.login_url();
+        LoginSession::new(&client.unlock("password").unwrap(), &url, None)
+            .unwrap()
+            .run(&mut server)
+            .unwrap();
+
+        // Rekey twice, so the server only knows the second previous identity
+        let rescue_code = client.rekey_identity("password", &rescue_code).unwrap();
+        client.rekey_identity("password", &rescue_code).unwrap();
+        let identity = client.unlock("password").unwrap();
+        assert_eq!(identity.previous_identity_count(), 2);
+
+        let url = server.login_url();
+        let outcome = LoginSession::new(&identity, &url, None)
+            .unwrap()
+            .run(&mut server)
+            .unwrap();
+        assert_eq!(
+            outcome,
+            LoginOutcome::PreviousIdentityUpdated {
+                success_url: Some("https://example.com/account".to_owned()),
+                previous_identity_index: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn unknown_after_all_previous_identities_associates() {
+        let (mut client, rescue_code) = test_client();
+        let rescue_code = client.rekey_identity("password", &rescue_code).unwrap();
+        client.rekey_identity("password", &rescue_code).unwrap();
+        let identity = client.unlock("password").unwrap();
+        let mut server = MockServer::new(TEST_HOST);
+
+        let url = server.login_url();
+        let mut session = LoginSession::new(&identity, &url, None).unwrap();
+        let (ident, outcome) = loop {
+            let request = session.next_request().unwrap();
+            let body = server
+                .post(session.post_url(), &encode_request(&request))
+                .unwrap();
+            if let Some(outcome) = session.handle_response(&body).unwrap() {
+                break (request, outcome);
+            }
+        };
+        assert!(matches!(outcome, LoginOutcome::IdentityAssociated { .. }));
+
+        // The server knows none of the previous identities, so none is sent
+        assert_eq!(ident.client_params.command, ClientCommand::Ident);
+        assert!(ident.client_params.previous_identity_key.is_none());
+        assert!(ident.previous_identity_signature.is_none());
+    }
+
+    #[test]
+    fn ask_answer_is_sent_with_ident() {
+        let identity = test_identity();
+        let mut server = MockServer::new(TEST_HOST);
+        server.set_ask(Some(format!(
+            "{}~{}~{}",
+            BASE64_URL_SAFE_NO_PAD.encode("Link this account?"),
+            BASE64_URL_SAFE_NO_PAD.encode("Yes"),
+            BASE64_URL_SAFE_NO_PAD.encode("No")
+        )));
+
+        let mut handler = ScriptedAskHandler::new(vec![AskAnswer::Button(2)]);
+        let url = server.login_url();
+        let outcome = LoginSession::new(&identity, &url, None)
+            .unwrap()
+            .run_with_ask_handler(&mut server, &mut handler)
+            .unwrap();
+        assert!(matches!(outcome, LoginOutcome::IdentityAssociated { .. }));
+        assert_eq!(handler.asked().len(), 1);
+        assert_eq!(handler.asked()[0].message, "Link this account?");
+        assert_eq!(server.last_button(), Some(2));
+
+        // Without a handler the question can't be answered
+        let url = server.login_url();
+        let error = LoginSession::new(&identity, &url, None)
+            .unwrap()
+            .run(&mut server)
+            .unwrap_err();
+        assert_eq!(error.kind(), &SqrlErrorKind::Cancelled);
+    }
+
+    #[test]
+    fn possible_mitm_warning_can_abort_ident() {
+        let (mut client, _) = test_client();
+        client
+            .update_config_settings(
+                "password",
+                Some(vec![ConfigOptions::WarnManInTheMiddle]),
+                None,
+                None,
+                None,
+            )
+            .unwrap();
+        let identity = client.unlock("password").unwrap();
+        let identity_key = |url: &str| identity.get_public_identity(url, None).unwrap();
+        let mut server = MockServer::new(TEST_HOST);
+        server.set_ips_match(false);
+
+        let mut warnings = Vec::new();
+        let url = server.login_url();
+        let mut session = LoginSession::new(&identity, &url, None).unwrap();
+        session.set_same_device(true);
+        let error = session
+            .run_with_handlers(
+                &mut server,
+                &mut ScriptedAskHandler::default(),
+                &mut |warning: SecurityWarning| {
+                    warnings.push(warning);
+                    WarningAction::Abort
+                },
+            )
+            .unwrap_err();
+        assert_eq!(error.kind(), &SqrlErrorKind::Cancelled);
+        assert_eq!(warnings, vec![SecurityWarning::PossibleMitm]);
+        assert!(server.account(&identity_key(&url)).is_none());
+
+        let url = server.login_url();
+        let mut session = LoginSession::new(&identity, &url, None).unwrap();
+        session.set_same_device(true);
+        let outcome = session
+            .run_with_handlers(
+                &mut server,
+                &mut ScriptedAskHandler::default(),
+                &mut |_: SecurityWarning| WarningAction::Continue,
+            )
+            .unwrap();
+        assert!(matches!(outcome, LoginOutcome::IdentityAssociated { .. }));
+
+        // Logins started on another device (such as from a QR code) aren't expected to match
+        let url = server.login_url();
+        let outcome = LoginSession::new(&identity, &url, None)
+            .unwrap()
+            .run(&mut server)
+            .unwrap();
+        assert!(matches!(outcome, LoginOutcome::LoggedIn { .. }));
+    }
+}
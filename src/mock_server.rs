@@ -0,0 +1,101 @@
+//! An in-memory SQRL server for testing clients
+
+use crate::{
+    server::{ClientPost, ServerAccount, SqrlServer},
+    transport::SqrlTransport,
+    Result,
+};
+use ed25519_dalek::VerifyingKey;
+use sqrl_protocol::client_request::ClientCommand;
+use std::net::{IpAddr, Ipv4Addr};
+
+// Login urls are issued to localhost, and requests from another device come from a
+// documentation address (RFC 5737)
+const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+const OTHER_DEVICE: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+
+/// A [`SqrlServer`] with extra controls for testing clients without a network
+///
+/// Every request is handled by the wrapped server. On top of that the mock
+/// remembers the button the client answered an ask= question with, and can
+/// make requests look like they came from a different IP than the login url
+/// was issued to.
+///
+/// This needs the `server` feature, which can be enabled just for tests:
+///
+/// ```toml
+/// [dev-dependencies]
+/// sqrl-client = { version = "0.1", features = ["server"] }
+/// ```
+#[derive(Debug)]
+pub struct MockServer {
+    server: SqrlServer,
+    last_button: Option<u8>,
+    ips_match: bool,
+}
+
+impl MockServer {
+    /// Create a server for the host (such as "example.com")
+    ///
+    /// Successful logins are sent to https://{host}/account.
+    pub fn new(host: &str) -> Self {
+        let mut server = SqrlServer::new(host);
+        server.set_success_url(&format!("https://{}/account", host));
+
+        MockServer {
+            server,
+            last_button: None,
+            ips_match: true,
+        }
+    }
+
+    /// Generate a sqrl:// url with a new nut, as would be shown on a login page
+    pub fn login_url(&mut self) -> String {
+        self.server.login_url(&LOCALHOST)
+    }
+
+    /// Look up what the server knows about an identity
+    pub fn account(&self, identity_key: &VerifyingKey) -> Option<&ServerAccount> {
+        self.server.account(identity_key)
+    }
+
+    /// Add an identity, as if it had been associated in an earlier login
+    pub fn add_account(&mut self, identity_key: &VerifyingKey, account: ServerAccount) {
+        self.server.add_account(identity_key, account);
+    }
+
+    /// Set the ask= value to send with every query response
+    pub fn set_ask(&mut self, ask: Option<String>) {
+        self.server.set_ask(ask);
+    }
+
+    /// Set whether requests come from the IP the login url was issued to (on
+    /// by default)
+    pub fn set_ips_match(&mut self, ips_match: bool) {
+        self.ips_match = ips_match;
+    }
+
+    /// The btn= value of the last ident the server received
+    pub fn last_button(&self) -> Option<u8> {
+        self.last_button
+    }
+}
+
+impl SqrlTransport for MockServer {
+    fn post(&mut self, url: &str, body: &str) -> Result<String> {
+        let client_ip = if self.ips_match {
+            LOCALHOST
+        } else {
+            OTHER_DEVICE
+        };
+        let response = self.server.handle_request(url, body, &client_ip);
+
+        if let Ok(post) = ClientPost::parse(body) {
+            let params = &post.request().client_params;
+            if params.command == ClientCommand::Ident {
+                self.last_button = params.button;
+            }
+        }
+        Ok(response.to_base64())
+    }
+}
@@ -0,0 +1,268 @@
+//! Generate and validate the nuts a SQRL server hands out
+
+use crate::secret::Secret;
+use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use rand::{rngs::OsRng, RngCore};
+use sha2::Sha256;
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::atomic::{AtomicU32, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+// A nut is a timestamp (4 bytes), counter (4 bytes), random bytes (8 bytes)
+// and an IP hash (4 bytes), followed by a truncated HMAC
+const RANDOM_LENGTH: usize = 8;
+const IP_HASH_OFFSET: usize = 4 + 4 + RANDOM_LENGTH;
+const PAYLOAD_LENGTH: usize = IP_HASH_OFFSET + 4;
+const TAG_LENGTH: usize = 8;
+
+/// Generates nuts that can later be checked by a [`NutValidator`] using the same key
+///
+/// Each nut holds the time it was issued, a counter, random bytes from the
+/// OS and a hash of the client's IP address, and is signed with an HMAC so it
+/// can't be forged. The counter keeps the nuts from one generator unique, and
+/// the random bytes keep nuts unpredictable and keep generators that share a
+/// key (in other processes, or after a restart) from issuing the same nut.
+#[derive(Debug)]
+pub struct NutGenerator {
+    key: Secret<[u8; 32]>,
+    counter: AtomicU32,
+}
+
+/// Whether a nut can be accepted
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NutValidation {
+    /// The nut was issued by this server, and has not been used before
+    Valid {
+        /// The nut was issued to the same IP address that sent it back
+        ips_match: bool,
+    },
+    /// The nut is too old
+    Expired,
+    /// The nut has already been used
+    Reused,
+    /// The nut was not issued with this key
+    Invalid,
+}
+
+/// Checks nuts made by a [`NutGenerator`], remembering which have been used
+#[derive(Debug)]
+pub struct NutValidator {
+    key: Secret<[u8; 32]>,
+    max_age: Duration,
+    used: HashMap<[u8; PAYLOAD_LENGTH], u64>,
+}
+
+impl NutGenerator {
+    /// Create a generator that signs nuts with the key
+    pub fn new(key: [u8; 32]) -> Self {
+        NutGenerator {
+            key: Secret::new(key),
+            counter: AtomicU32::new(0),
+        }
+    }
+
+    /// Generate a new nut for a client
+    pub fn generate(&self, client_ip: &IpAddr) -> String {
+        self.generate_at(client_ip, SystemTime::now())
+    }
+
+    fn generate_at(&self, client_ip: &IpAddr, now: SystemTime) -> String {
+        let counter = self.counter.fetch_add(1, Ordering::Relaxed).wrapping_add(1);
+        let mut random = [0; RANDOM_LENGTH];
+        OsRng.fill_bytes(&mut random);
+
+        let mut nut = Vec::with_capacity(PAYLOAD_LENGTH + TAG_LENGTH);
+        nut.extend_from_slice(&(seconds_since_epoch(now) as u32).to_le_bytes());
+        nut.extend_from_slice(&counter.to_le_bytes());
+        nut.extend_from_slice(&random);
+        nut.extend_from_slice(&ip_hash(&self.key, client_ip));
+        let nut_tag = tag(&self.key, &nut);
+        nut.extend_from_slice(&nut_tag);
+
+        BASE64_URL_SAFE_NO_PAD.encode(nut)
+    }
+}
+
+impl NutValidator {
+    /// Create a validator for nuts signed with the key, which accepts nuts up
+    /// to the max age
+    pub fn new(key: [u8; 32], max_age: Duration) -> Self {
+        NutValidator {
+            key: Secret::new(key),
+            max_age,
+            used: HashMap::new(),
+        }
+    }
+
+    /// Check a nut sent by a client, marking it as used if it is valid
+    pub fn validate(&mut self, nut: &str, client_ip: &IpAddr) -> NutValidation {
+        self.validate_at(nut, client_ip, SystemTime::now())
+    }
+
+    fn validate_at(&mut self, nut: &str, client_ip: &IpAddr, now: SystemTime) -> NutValidation {
+        let now = seconds_since_epoch(now);
+
+        // Nuts that have expired can't be reused, so there's no need to remember them
+        let max_age = self.max_age.as_secs();
+        self.used
+            .retain(|_, issued| now.saturating_sub(*issued) <= max_age);
+
+        let nut = match BASE64_URL_SAFE_NO_PAD.decode(nut) {
+            Ok(nut) if nut.len() == PAYLOAD_LENGTH + TAG_LENGTH => nut,
+            _ => return NutValidation::Invalid,
+        };
+        let (payload, nut_tag) = nut.split_at(PAYLOAD_LENGTH);
+        let mut hmac = hmac(&self.key);
+        hmac.update(payload);
+        if hmac.verify_truncated_left(nut_tag).is_err() {
+            return NutValidation::Invalid;
+        }
+
+        let mut timestamp = [0; 4];
+        timestamp.copy_from_slice(&payload[..4]);
+        let issued = u64::from(u32::from_le_bytes(timestamp));
+        if issued > now || now - issued > max_age {
+            return NutValidation::Expired;
+        }
+
+        let mut key = [0; PAYLOAD_LENGTH];
+        key.copy_from_slice(payload);
+        if self.used.insert(key, issued).is_some() {
+            return NutValidation::Reused;
+        }
+
+        NutValidation::Valid {
+            ips_match: payload[IP_HASH_OFFSET..] == ip_hash(&self.key, client_ip),
+        }
+    }
+}
+
+fn hmac(key: &[u8; 32]) -> Hmac<Sha256> {
+    // A 32 byte key is always a valid HMAC key
+    Hmac::<Sha256>::new_from_slice(key).unwrap()
+}
+
+fn tag(key: &[u8; 32], payload: &[u8]) -> [u8; TAG_LENGTH] {
+    let mut hmac = hmac(key);
+    hmac.update(payload);
+    let mut tag = [0; TAG_LENGTH];
+    tag.copy_from_slice(&hmac.finalize().into_bytes()[..TAG_LENGTH]);
+    tag
+}
+
+fn ip_hash(key: &[u8; 32], ip: &IpAddr) -> [u8; 4] {
+    let mut hmac = hmac(key);
+    hmac.update(b"ip");
+    match ip {
+        IpAddr::V4(ip) => hmac.update(&ip.octets()),
+        IpAddr::V6(ip) => hmac.update(&ip.octets()),
+    }
+    let mut hash = [0; 4];
+    hash.copy_from_slice(&hmac.finalize().into_bytes()[..4]);
+    hash
+}
+
+fn seconds_since_epoch(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const TEST_KEY: [u8; 32] = [3; 32];
+    const MAX_AGE: Duration = Duration::from_secs(300);
+
+    fn client_ip() -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))
+    }
+
+    #[test]
+    fn nut_is_valid_once() {
+        let generator = NutGenerator::new(TEST_KEY);
+        let mut validator = NutValidator::new(TEST_KEY, MAX_AGE);
+        let nut = generator.generate(&client_ip());
+        assert_ne!(nut, generator.generate(&client_ip()));
+
+        assert_eq!(
+            validator.validate(&nut, &client_ip()),
+            NutValidation::Valid { ips_match: true }
+        );
+        assert_eq!(
+            validator.validate(&nut, &client_ip()),
+            NutValidation::Reused
+        );
+
+        let nut = generator.generate(&client_ip());
+        assert_eq!(
+            validator.validate(&nut, &IpAddr::V4(Ipv4Addr::new(198, 51, 100, 7))),
+            NutValidation::Valid { ips_match: false }
+        );
+    }
+
+    #[test]
+    fn nut_holds_counter() {
+        let generator = NutGenerator::new(TEST_KEY);
+        let counter = |nut: &str| {
+            let nut = BASE64_URL_SAFE_NO_PAD.decode(nut).unwrap();
+            u32::from_le_bytes(nut[4..8].try_into().unwrap())
+        };
+
+        assert_eq!(counter(&generator.generate(&client_ip())), 1);
+        assert_eq!(counter(&generator.generate(&client_ip())), 2);
+    }
+
+    #[test]
+    fn generators_with_the_same_key_issue_different_nuts() {
+        let first = NutGenerator::new(TEST_KEY);
+        let second = NutGenerator::new(TEST_KEY);
+        let now = SystemTime::now();
+        let nut = first.generate_at(&client_ip(), now);
+        assert_ne!(nut, second.generate_at(&client_ip(), now));
+
+        // Both are valid, and one doesn't use up the other
+        let mut validator = NutValidator::new(TEST_KEY, MAX_AGE);
+        assert_eq!(
+            validator.validate(&nut, &client_ip()),
+            NutValidation::Valid { ips_match: true }
+        );
+        assert_eq!(
+            validator.validate(&second.generate_at(&client_ip(), now), &client_ip()),
+            NutValidation::Valid { ips_match: true }
+        );
+    }
+
+    #[test]
+    fn old_nut_is_expired() {
+        let generator = NutGenerator::new(TEST_KEY);
+        let mut validator = NutValidator::new(TEST_KEY, MAX_AGE);
+        let issued = SystemTime::now() - Duration::from_secs(301);
+        let nut = generator.generate_at(&client_ip(), issued);
+        assert_eq!(
+            validator.validate(&nut, &client_ip()),
+            NutValidation::Expired
+        );
+    }
+
+    #[test]
+    fn forged_nut_is_invalid() {
+        let generator = NutGenerator::new([4; 32]);
+        let mut validator = NutValidator::new(TEST_KEY, MAX_AGE);
+        let nut = generator.generate(&client_ip());
+        assert_eq!(
+            validator.validate(&nut, &client_ip()),
+            NutValidation::Invalid
+        );
+        assert_eq!(
+            validator.validate("not a nut", &client_ip()),
+            NutValidation::Invalid
+        );
+    }
+}
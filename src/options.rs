@@ -0,0 +1,82 @@
+//! Options for the SqrlClient operations that generate keys
+
+use crate::{
+    progress::{NoProgress, ProgressHandler},
+    ScryptPolicy,
+};
+use aes_gcm::aead::OsRng;
+use rand_core::CryptoRngCore;
+use std::fmt;
+
+/// The random number generator, progress handler and EnScrypt policy used by
+/// an operation, leaving any that are None at their defaults
+///
+/// ```rust
+/// use rand::{rngs::StdRng, SeedableRng};
+/// use sqrl_client::{
+///     progress::{EnScryptProgress, ProgressAction},
+///     OperationOptions, ScryptPolicy, SqrlClient,
+/// };
+///
+/// let mut rng = StdRng::seed_from_u64(42);
+/// let mut progress = |progress: EnScryptProgress| {
+///     println!("{:?}", progress);
+///     ProgressAction::Continue
+/// };
+/// let (client, _) = SqrlClient::new_with_options(
+///     "password",
+///     OperationOptions {
+///         rng: Some(&mut rng),
+///         progress: Some(&mut progress),
+///         // A low iteration count makes tests fast, but should never be used for real identities
+///         policy: Some(ScryptPolicy::with_iterations(1)),
+///     },
+/// )
+/// .unwrap();
+/// ```
+#[derive(Default)]
+pub struct OperationOptions<'a> {
+    /// The random number generator used for all keys, salts and IVs, instead
+    /// of the operating system's
+    pub rng: Option<&'a mut dyn CryptoRngCore>,
+    /// The handler that the progress of EnScrypt is reported to
+    pub progress: Option<&'a mut dyn ProgressHandler>,
+    /// The EnScrypt cost parameters for a new identity, instead of the
+    /// [`ScryptPolicy`] defaults
+    ///
+    /// This is only used by operations that create a client (creating,
+    /// importing or recreating an identity), and is kept as the client's
+    /// [`crate::SqrlClient::scrypt_policy`]. Other operations use the policy
+    /// the client already has.
+    pub policy: Option<ScryptPolicy>,
+}
+
+impl OperationOptions<'_> {
+    // Run the operation with the options, filling in the defaults for any that aren't set
+    pub(crate) fn run<T>(
+        self,
+        operation: impl FnOnce(
+            &mut dyn CryptoRngCore,
+            &mut dyn ProgressHandler,
+            Option<ScryptPolicy>,
+        ) -> T,
+    ) -> T {
+        let mut os_rng = OsRng;
+        let mut no_progress = NoProgress;
+        operation(
+            self.rng.unwrap_or(&mut os_rng),
+            self.progress.unwrap_or(&mut no_progress),
+            self.policy,
+        )
+    }
+}
+
+impl fmt::Debug for OperationOptions<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("OperationOptions")
+            .field("has_rng", &self.rng.is_some())
+            .field("has_progress", &self.progress.is_some())
+            .field("policy", &self.policy)
+            .finish()
+    }
+}
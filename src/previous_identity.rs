@@ -1,6 +1,7 @@
 use crate::{
-    error::SqrlError, readable_vector::ReadableVector, writable_datablock::WritableDataBlock,
-    AesVerificationData, DataType, IdentityKey, Result, EMPTY_NONCE,
+    error::SqrlError, readable_vector::ReadableVector, secret::Secret,
+    writable_datablock::WritableDataBlock, AesVerificationData, DataType, IdentityKey, Result,
+    EMPTY_NONCE,
 };
 use aes_gcm::{
     aead::{AeadMut, Payload},
@@ -27,13 +28,17 @@ impl PreviousIdentityData {
         }
     }
 
+    pub(crate) fn edition(&self) -> u16 {
+        self.edition
+    }
+
     pub(crate) fn add_previous_identity(
         &mut self,
         identity_master_key: &[u8],
-        key: IdentityKey,
+        key: Secret<IdentityKey>,
     ) -> Result<()> {
         // First decrypt the existing data
-        let mut unencrypted_keys: VecDeque<IdentityKey>;
+        let mut unencrypted_keys: VecDeque<Secret<IdentityKey>>;
         if self.edition > 0 {
             unencrypted_keys = self.decrypt_previous_identities(identity_master_key)?;
         } else {
@@ -53,7 +58,7 @@ impl PreviousIdentityData {
         &mut self,
         current_identity_master_key: &[u8],
         new_identity_master_key: &[u8],
-        current_identity_unlock_key: Option<IdentityKey>,
+        current_identity_unlock_key: Option<Secret<IdentityKey>>,
     ) -> Result<()> {
         let mut unencrypted_keys = self.decrypt_previous_identities(current_identity_master_key)?;
 
@@ -67,23 +72,10 @@ impl PreviousIdentityData {
         self.encrypt_previous_identities(unencrypted_keys, new_identity_master_key)
     }
 
-    pub(crate) fn get_previous_identity(
+    pub(crate) fn decrypt_previous_identities(
         &self,
         identity_master_key: &[u8],
-        index: usize,
-    ) -> Result<Option<IdentityKey>> {
-        let keys = self.decrypt_previous_identities(identity_master_key)?;
-        if index < keys.len() {
-            Ok(Some(keys[index]))
-        } else {
-            Ok(None)
-        }
-    }
-
-    fn decrypt_previous_identities(
-        &self,
-        identity_master_key: &[u8],
-    ) -> Result<VecDeque<IdentityKey>> {
+    ) -> Result<VecDeque<Secret<IdentityKey>>> {
         // Append the various previous identities and the verification data
         let mut encrypted_data = Vec::new();
         for key in self.previous_identity_unlock_keys.iter() {
@@ -102,13 +94,13 @@ impl PreviousIdentityData {
             aad: &self.aad()?,
         };
 
-        let unencrypted_data = aes.decrypt(&EMPTY_NONCE.into(), payload)?;
+        let unencrypted_data = Secret::new(aes.decrypt(&EMPTY_NONCE.into(), payload)?);
         let mut result = VecDeque::new();
-        let mut iter = unencrypted_data.into_iter();
+        let mut iter = unencrypted_data.iter();
         for _ in 0..self.edition {
-            let mut key: IdentityKey = [0; 32];
-            for i in &mut key {
-                *i = iter.next().unwrap();
+            let mut key = Secret::new([0; 32]);
+            for i in key.iter_mut() {
+                *i = *iter.next().unwrap();
             }
             result.push_back(key);
         }
@@ -118,7 +110,7 @@ impl PreviousIdentityData {
 
     fn encrypt_previous_identities(
         &mut self,
-        unencrypted_keys: VecDeque<IdentityKey>,
+        unencrypted_keys: VecDeque<Secret<IdentityKey>>,
         identity_master_key: &[u8],
     ) -> Result<()> {
         let num_keys: u16 = match unencrypted_keys.len().try_into() {
@@ -126,9 +118,16 @@ impl PreviousIdentityData {
             Err(_) => return Err(SqrlError::new("Too many previous keys".to_owned())),
         };
 
+        // The edition is part of the AAD, so it needs to be updated before encrypting
+        self.edition = num_keys;
+
         let mut aes = Aes256Gcm::new(identity_master_key.into());
+        let mut to_encrypt = Secret::new(Vec::with_capacity(unencrypted_keys.len() * 32));
+        for key in &unencrypted_keys {
+            to_encrypt.extend_from_slice(&**key);
+        }
         let payload = Payload {
-            msg: &unencrypted_keys.into_iter().flatten().collect::<Vec<u8>>(),
+            msg: &to_encrypt,
             aad: &self.aad()?,
         };
 
@@ -144,7 +143,11 @@ impl PreviousIdentityData {
             result.push_back(key);
         }
 
-        self.edition = num_keys;
+        // Whatever is left over is the verification tag
+        for i in &mut self.verification_data {
+            *i = iter.next().unwrap();
+        }
+
         self.previous_identity_unlock_keys = result;
 
         Ok(())
@@ -204,3 +207,49 @@ impl WritableDataBlock for PreviousIdentityData {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_MASTER_KEY: [u8; 32] = [1; 32];
+
+    #[test]
+    fn decrypt_added_identities() {
+        let mut previous = PreviousIdentityData::new();
+        previous
+            .add_previous_identity(&TEST_MASTER_KEY, Secret::new([2; 32]))
+            .unwrap();
+        previous
+            .add_previous_identity(&TEST_MASTER_KEY, Secret::new([3; 32]))
+            .unwrap();
+
+        // The edition is part of the AAD, so it has to match the number of keys encrypted
+        let keys = previous
+            .decrypt_previous_identities(&TEST_MASTER_KEY)
+            .unwrap();
+        assert_eq!(previous.edition(), 2);
+        assert_eq!(keys.len(), 2);
+        assert_eq!(*keys[0], [3; 32]);
+        assert_eq!(*keys[1], [2; 32]);
+    }
+
+    #[test]
+    fn decrypt_after_write_and_read() {
+        let mut previous = PreviousIdentityData::new();
+        previous
+            .add_previous_identity(&TEST_MASTER_KEY, Secret::new([2; 32]))
+            .unwrap();
+
+        // The verification tag is written with the block, so it is needed to decrypt it again
+        let mut binary = Vec::new();
+        previous.to_binary(&mut binary).unwrap();
+        let mut binary: VecDeque<u8> = binary.into_iter().skip(4).collect();
+        let read = PreviousIdentityData::from_binary(&mut binary).unwrap();
+        assert_eq!(read, previous);
+
+        let keys = read.decrypt_previous_identities(&TEST_MASTER_KEY).unwrap();
+        assert_eq!(keys.len(), 1);
+        assert_eq!(*keys[0], [2; 32]);
+    }
+}
@@ -0,0 +1,62 @@
+//! Progress reporting and cancellation for the slow EnScrypt key derivation
+
+use std::time::Duration;
+
+/// How far along a single EnScrypt run is
+///
+/// Operations that need to run EnScrypt more than once (such as rekeying an
+/// identity) report the progress of each run separately.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EnScryptProgress {
+    /// Running a known number of iterations (decrypting existing data)
+    Iterations {
+        /// The number of iterations that have completed
+        done: u32,
+        /// The total number of iterations that will be run
+        total: u32,
+    },
+    /// Running for a time budget (encrypting data with a new password or rescue code)
+    Time {
+        /// The time spent so far
+        elapsed: Duration,
+        /// The time EnScrypt will run for
+        budget: Duration,
+    },
+}
+
+/// What a [`ProgressHandler`] wants to happen after being told the progress
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProgressAction {
+    /// Keep running EnScrypt
+    Continue,
+    /// Stop running EnScrypt, failing the operation with
+    /// [`SqrlErrorKind::Cancelled`](crate::error::SqrlErrorKind::Cancelled)
+    Cancel,
+}
+
+/// Receives the progress of EnScrypt runs, and can cancel them
+///
+/// This is implemented for any `FnMut(EnScryptProgress) -> ProgressAction`
+/// closure.
+pub trait ProgressHandler {
+    /// Called at the start of a run and after each EnScrypt iteration
+    fn on_progress(&mut self, progress: EnScryptProgress) -> ProgressAction;
+}
+
+impl<F> ProgressHandler for F
+where
+    F: FnMut(EnScryptProgress) -> ProgressAction,
+{
+    fn on_progress(&mut self, progress: EnScryptProgress) -> ProgressAction {
+        self(progress)
+    }
+}
+
+// Used by the operations that don't take a progress handler
+pub(crate) struct NoProgress;
+
+impl ProgressHandler for NoProgress {
+    fn on_progress(&mut self, _progress: EnScryptProgress) -> ProgressAction {
+        ProgressAction::Continue
+    }
+}
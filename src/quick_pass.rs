@@ -0,0 +1,188 @@
+use crate::{
+    error::{SqrlError, SqrlErrorKind},
+    identity_information::EncryptedKeyPair,
+    progress::ProgressHandler,
+    scrypt_config::{en_scrypt, mut_en_scrypt, ScryptConfig},
+    secret::Secret,
+    Result,
+};
+use aes_gcm::{
+    aead::{Aead, Payload},
+    Aes256Gcm, KeyInit,
+};
+use rand_core::CryptoRngCore;
+use std::{
+    io::Write,
+    time::{Duration, Instant},
+};
+
+// The QuickPass is meant to be fast, so only run EnScrypt for about a second
+const QUICK_PASS_SCRYPT_TIME: u8 = 1;
+const QUICK_PASS_AAD: &[u8] = b"sqrl-quick-pass";
+
+/// The identity keys re-encrypted in RAM using only the first few characters
+/// of the password (the "hint"), as described by the SQRL QuickPass feature
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct QuickPass {
+    scrypt_config: ScryptConfig,
+    aes_gcm_iv: [u8; 12],
+    encrypted_data: Vec<u8>,
+    idle_timeout: Option<Duration>,
+    // When the QuickPass stops working unless it is used again, if ever
+    expires_at: Option<Instant>,
+}
+
+impl QuickPass {
+    pub(crate) fn new(
+        hint: &str,
+        keys: &EncryptedKeyPair,
+        idle_timeout_min: u16,
+        rng: &mut dyn CryptoRngCore,
+        progress: &mut dyn ProgressHandler,
+    ) -> Result<Self> {
+        let mut scrypt_config = ScryptConfig::new(rng);
+        let key = mut_en_scrypt(
+            hint.as_bytes(),
+            &mut scrypt_config,
+            QUICK_PASS_SCRYPT_TIME,
+            progress,
+        )?;
+
+        let mut to_encrypt = Secret::new(Vec::with_capacity(64));
+        to_encrypt.write_all(&*keys.identity_master_key)?;
+        to_encrypt.write_all(&*keys.identity_lock_key)?;
+
+        let mut aes_gcm_iv = [0; 12];
+        rng.fill_bytes(&mut aes_gcm_iv);
+        let aes = Aes256Gcm::new(key.as_slice().into());
+        let payload = Payload {
+            msg: &to_encrypt,
+            aad: QUICK_PASS_AAD,
+        };
+        let encrypted_data = aes.encrypt(&aes_gcm_iv.into(), payload)?;
+
+        let idle_timeout = match idle_timeout_min {
+            0 => None,
+            minutes => Some(Duration::from_secs(u64::from(minutes) * 60)),
+        };
+
+        Ok(QuickPass {
+            scrypt_config,
+            aes_gcm_iv,
+            encrypted_data,
+            idle_timeout,
+            expires_at: idle_timeout.map(|timeout| Instant::now() + timeout),
+        })
+    }
+
+    pub(crate) fn is_expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| Instant::now() >= expires_at)
+    }
+
+    #[cfg(test)]
+    pub(crate) fn expire(&mut self) {
+        self.expires_at = Some(Instant::now());
+    }
+
+    pub(crate) fn decrypt(
+        &mut self,
+        hint: &str,
+        progress: &mut dyn ProgressHandler,
+    ) -> Result<EncryptedKeyPair> {
+        if self.is_expired() {
+            return Err(SqrlError::with_kind(
+                SqrlErrorKind::QuickPassUnavailable,
+                "QuickPass has expired".to_owned(),
+            ));
+        }
+
+        let key = en_scrypt(hint.as_bytes(), &self.scrypt_config, progress)?;
+        let aes = Aes256Gcm::new(key.as_slice().into());
+        let payload = Payload {
+            msg: &self.encrypted_data,
+            aad: QUICK_PASS_AAD,
+        };
+        let decrypted_data =
+            Secret::new(aes.decrypt(&self.aes_gcm_iv.into(), payload).map_err(|_| {
+                SqrlError::with_kind(
+                    SqrlErrorKind::WrongPassword,
+                    "Invalid QuickPass hint".to_owned(),
+                )
+            })?);
+
+        self.expires_at = self.idle_timeout.map(|timeout| Instant::now() + timeout);
+        EncryptedKeyPair::from_slice(&decrypted_data)
+    }
+}
+
+// The hint is the first hint_length characters of the password
+pub(crate) fn get_hint(password: &str, hint_length: u8) -> &str {
+    match password.char_indices().nth(hint_length.into()) {
+        Some((index, _)) => &password[..index],
+        None => password,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::progress::NoProgress;
+    use aes_gcm::aead::OsRng;
+
+    #[test]
+    fn get_hint_uses_characters() {
+        assert_eq!(get_hint("password", 4), "pass");
+        assert_eq!(get_hint("pässwörd", 4), "päss");
+        assert_eq!(get_hint("pw", 4), "pw");
+    }
+
+    #[test]
+    fn quick_pass_decrypts_with_hint() {
+        let keys = EncryptedKeyPair {
+            identity_master_key: Secret::new([1; 32]),
+            identity_lock_key: Secret::new([2; 32]),
+        };
+        let mut quick_pass = QuickPass::new("pass", &keys, 5, &mut OsRng, &mut NoProgress).unwrap();
+
+        assert_eq!(
+            quick_pass
+                .decrypt("fail", &mut NoProgress)
+                .unwrap_err()
+                .kind(),
+            &SqrlErrorKind::WrongPassword
+        );
+        assert_eq!(quick_pass.decrypt("pass", &mut NoProgress).unwrap(), keys);
+    }
+
+    #[test]
+    fn quick_pass_expires() {
+        let keys = EncryptedKeyPair {
+            identity_master_key: Secret::new([1; 32]),
+            identity_lock_key: Secret::new([2; 32]),
+        };
+        let mut quick_pass = QuickPass::new("pass", &keys, 1, &mut OsRng, &mut NoProgress).unwrap();
+        assert!(!quick_pass.is_expired());
+        quick_pass.expire();
+
+        assert!(quick_pass.is_expired());
+        assert_eq!(
+            quick_pass
+                .decrypt("pass", &mut NoProgress)
+                .unwrap_err()
+                .kind(),
+            &SqrlErrorKind::QuickPassUnavailable
+        );
+    }
+
+    #[test]
+    fn quick_pass_without_idle_timeout_never_expires() {
+        let keys = EncryptedKeyPair {
+            identity_master_key: Secret::new([1; 32]),
+            identity_lock_key: Secret::new([2; 32]),
+        };
+        let mut quick_pass = QuickPass::new("pass", &keys, 0, &mut OsRng, &mut NoProgress).unwrap();
+        assert!(!quick_pass.is_expired());
+        assert_eq!(quick_pass.decrypt("pass", &mut NoProgress).unwrap(), keys);
+    }
+}
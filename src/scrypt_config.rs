@@ -1,12 +1,131 @@
-use crate::{common::xor, error::SqrlError, readable_vector::ReadableVector, Result};
+use crate::{
+    common::xor,
+    error::{SqrlError, SqrlErrorKind},
+    progress::{EnScryptProgress, ProgressAction, ProgressHandler},
+    readable_vector::ReadableVector,
+    secret::Secret,
+    Result,
+};
 use byteorder::{LittleEndian, WriteBytesExt};
-use rand::{prelude::StdRng, RngCore, SeedableRng};
+use rand_core::CryptoRngCore;
 use scrypt::{scrypt, Params};
-use std::{collections::VecDeque, convert::TryInto, io::Write, time::Instant};
+use std::{
+    collections::VecDeque,
+    convert::TryInto,
+    io::Write,
+    time::{Duration, Instant},
+};
 
 pub(crate) const SCRYPT_DEFAULT_LOG_N: u8 = 9;
 pub(crate) const SCRYPT_DEFAULT_R: u32 = 256;
 pub(crate) const SCRYPT_DEFAULT_P: u32 = 1;
+const SCRYPT_DEFAULT_PASSWORD_TIME: u8 = 5;
+const SCRYPT_DEFAULT_RESCUE_CODE_TIME: u8 = 5;
+
+/// The EnScrypt cost parameters used when encrypting a new identity
+///
+/// The defaults match the SQRL spec. Only the log N factor and the resulting
+/// iteration count are stored in the identity, so any policy produces files
+/// that other SQRL clients can read.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScryptPolicy {
+    /// The scrypt log N factor (memory and CPU cost) of each EnScrypt iteration
+    pub log_n_factor: u8,
+    /// How long (in seconds) to run EnScrypt when encrypting with the password
+    pub password_time_sec: u8,
+    /// How long (in seconds) to run EnScrypt when encrypting with the rescue code
+    pub rescue_code_time_sec: u8,
+    /// Run exactly this many EnScrypt iterations instead of using the time budgets
+    ///
+    /// This is meant for fast test runs, as a low count makes the identity
+    /// much easier to brute force.
+    pub iteration_count: Option<u32>,
+}
+
+impl ScryptPolicy {
+    /// A policy that runs a fixed number of iterations, with the default log N factor
+    pub fn with_iterations(iteration_count: u32) -> Self {
+        ScryptPolicy {
+            iteration_count: Some(iteration_count),
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn validate(&self) -> Result<()> {
+        if self.iteration_count == Some(0) {
+            return Err(SqrlError::new(
+                "The EnScrypt iteration count must be at least 1".to_owned(),
+            ));
+        }
+
+        // Without a fixed count, a zero time budget stops after a single iteration
+        if self.iteration_count.is_none()
+            && (self.password_time_sec == 0 || self.rescue_code_time_sec == 0)
+        {
+            return Err(SqrlError::new(
+                "The EnScrypt time budgets must be at least 1 second".to_owned(),
+            ));
+        }
+
+        // Make sure scrypt will accept the log N factor before spending any time on it
+        Params::new(self.log_n_factor, SCRYPT_DEFAULT_R, SCRYPT_DEFAULT_P, 32)?;
+        Ok(())
+    }
+}
+
+impl Default for ScryptPolicy {
+    fn default() -> Self {
+        ScryptPolicy {
+            log_n_factor: SCRYPT_DEFAULT_LOG_N,
+            password_time_sec: SCRYPT_DEFAULT_PASSWORD_TIME,
+            rescue_code_time_sec: SCRYPT_DEFAULT_RESCUE_CODE_TIME,
+            iteration_count: None,
+        }
+    }
+}
+
+/// The lowest EnScrypt cost parameters an identity is expected to have
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScryptMinimum {
+    /// The lowest acceptable log N factor
+    pub log_n_factor: u8,
+    /// The lowest acceptable iteration count for the password protected block
+    pub password_iterations: u32,
+    /// The lowest acceptable iteration count for the rescue code protected block
+    pub rescue_code_iterations: u32,
+}
+
+/// The blocks of an identity that are protected using EnScrypt
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ScryptBlock {
+    /// The block encrypted using the password
+    Password,
+    /// The block encrypted using the rescue code
+    RescueCode,
+}
+
+/// A warning that an identity's stored EnScrypt parameters are below a minimum
+#[derive(Clone, Debug, PartialEq)]
+pub enum ScryptWarning {
+    /// The log N factor is below the minimum
+    LogNFactorTooLow {
+        /// The block with the low log N factor
+        block: ScryptBlock,
+        /// The log N factor stored in the block
+        log_n_factor: u8,
+        /// The minimum log N factor
+        minimum: u8,
+    },
+    /// The iteration count is below the minimum
+    IterationsTooLow {
+        /// The block with the low iteration count
+        block: ScryptBlock,
+        /// The iteration count stored in the block
+        iterations: u32,
+        /// The minimum iteration count
+        minimum: u32,
+    },
+}
 
 #[derive(Clone, Debug, PartialEq)]
 pub(crate) struct ScryptConfig {
@@ -16,10 +135,9 @@ pub(crate) struct ScryptConfig {
 }
 
 impl ScryptConfig {
-    pub(crate) fn new() -> Self {
-        let mut random = StdRng::from_entropy();
+    pub(crate) fn new(rng: &mut dyn CryptoRngCore) -> Self {
         let mut salt: [u8; 16] = [0; 16];
-        random.fill_bytes(&mut salt);
+        rng.fill_bytes(&mut salt);
 
         ScryptConfig {
             random_salt: salt,
@@ -28,6 +146,39 @@ impl ScryptConfig {
         }
     }
 
+    pub(crate) fn from_policy(policy: &ScryptPolicy, rng: &mut dyn CryptoRngCore) -> Self {
+        ScryptConfig {
+            log_n_factor: policy.log_n_factor,
+            iteration_factor: policy.iteration_count,
+            ..ScryptConfig::new(rng)
+        }
+    }
+
+    pub(crate) fn check_minimum(
+        &self,
+        block: ScryptBlock,
+        minimum_log_n_factor: u8,
+        minimum_iterations: u32,
+        warnings: &mut Vec<ScryptWarning>,
+    ) {
+        if self.log_n_factor < minimum_log_n_factor {
+            warnings.push(ScryptWarning::LogNFactorTooLow {
+                block,
+                log_n_factor: self.log_n_factor,
+                minimum: minimum_log_n_factor,
+            });
+        }
+
+        let iterations = self.iteration_factor.unwrap_or(0);
+        if iterations < minimum_iterations {
+            warnings.push(ScryptWarning::IterationsTooLow {
+                block,
+                iterations,
+                minimum: minimum_iterations,
+            });
+        }
+    }
+
     pub(crate) fn from_binary(binary: &mut VecDeque<u8>) -> Result<Self> {
         Ok(ScryptConfig {
             random_salt: binary.next_sub_array(16)?.as_slice().try_into()?,
@@ -59,60 +210,60 @@ pub(crate) fn mut_en_scrypt(
     password: &[u8],
     scrypt_config: &mut ScryptConfig,
     pw_verify_sec: u8,
-) -> Result<[u8; 32]> {
-    let mut output: [u8; 32] = [0; 32];
-    let mut input: [u8; 32] = [0; 32];
-    let mut temp: [u8; 32] = [0; 32];
+    progress: &mut dyn ProgressHandler,
+) -> Result<Secret<[u8; 32]>> {
+    if scrypt_config.iteration_factor.is_some() {
+        return en_scrypt(password, scrypt_config, progress);
+    }
 
-    let params = Params::new(
-        scrypt_config.log_n_factor,
-        SCRYPT_DEFAULT_R,
-        SCRYPT_DEFAULT_P,
-        32,
-    )?;
+    // Run for the time budget, and then store how many iterations it took
+    let budget = Duration::from_secs(pw_verify_sec.into());
+    let now = Instant::now();
+    let (output, count) = run_en_scrypt(password, scrypt_config, |done| {
+        let elapsed = now.elapsed();
+        report(progress, EnScryptProgress::Time { elapsed, budget })?;
+        Ok(done == 0 || elapsed.as_secs() < pw_verify_sec.into())
+    })?;
+    scrypt_config.iteration_factor = Some(count);
 
-    match scrypt_config.iteration_factor {
-        Some(factor) => {
-            for i in 0..factor {
-                if i == 0 {
-                    scrypt(password, &scrypt_config.random_salt, &params, &mut temp)?;
-                } else {
-                    scrypt(password, &input, &params, &mut temp)?;
-                }
-
-                xor(&mut output, &temp);
-                input = temp;
-            }
-        }
+    Ok(output)
+}
+
+pub(crate) fn en_scrypt(
+    password: &[u8],
+    scrypt_config: &ScryptConfig,
+    progress: &mut dyn ProgressHandler,
+) -> Result<Secret<[u8; 32]>> {
+    let total = match scrypt_config.iteration_factor {
+        Some(factor) => factor,
         None => {
-            let now = Instant::now();
-            let mut count = 0;
-            loop {
-                if count == 0 {
-                    scrypt(password, &scrypt_config.random_salt, &params, &mut temp)?;
-                } else {
-                    scrypt(password, &input, &params, &mut temp)?;
-                }
-
-                xor(&mut output, &temp);
-                input = temp;
-                count += 1;
-
-                if now.elapsed().as_secs() >= pw_verify_sec.into() {
-                    break;
-                }
-            }
-            scrypt_config.iteration_factor = Some(count);
+            return Err(SqrlError::new(
+                "ScryptConfig iteration factor not set".to_string(),
+            ))
         }
-    }
+    };
+
+    let (output, _) = run_en_scrypt(password, scrypt_config, |done| {
+        report(progress, EnScryptProgress::Iterations { done, total })?;
+        Ok(done < total)
+    })?;
 
     Ok(output)
 }
 
-pub(crate) fn en_scrypt(password: &[u8], scrypt_config: &ScryptConfig) -> Result<[u8; 32]> {
-    let mut output: [u8; 32] = [0; 32];
-    let mut input: [u8; 32] = [0; 32];
-    let mut temp: [u8; 32] = [0; 32];
+// Run scrypt iterations, XORing each result into the output, for as long as
+// keep_running (called with the number of completed iterations) returns true
+fn run_en_scrypt<F>(
+    password: &[u8],
+    scrypt_config: &ScryptConfig,
+    mut keep_running: F,
+) -> Result<(Secret<[u8; 32]>, u32)>
+where
+    F: FnMut(u32) -> Result<bool>,
+{
+    let mut output = Secret::new([0u8; 32]);
+    let mut input = Secret::new([0u8; 32]);
+    let mut temp = Secret::new([0u8; 32]);
 
     let params = Params::new(
         scrypt_config.log_n_factor,
@@ -121,25 +272,76 @@ pub(crate) fn en_scrypt(password: &[u8], scrypt_config: &ScryptConfig) -> Result
         32,
     )?;
 
-    match scrypt_config.iteration_factor {
-        Some(factor) => {
-            for i in 0..factor {
-                if i == 0 {
-                    scrypt(password, &scrypt_config.random_salt, &params, &mut temp)?;
-                } else {
-                    scrypt(password, &input, &params, &mut temp)?;
-                }
-
-                xor(&mut output, &temp);
-                input = temp;
-            }
+    let mut count = 0;
+    while keep_running(count)? {
+        if count == 0 {
+            scrypt(password, &scrypt_config.random_salt, &params, &mut *temp)?;
+        } else {
+            scrypt(password, &*input, &params, &mut *temp)?;
         }
-        None => {
-            return Err(SqrlError::new(
-                "ScryptConfig iteration factor not set".to_string(),
-            ))
+
+        xor(&mut *output, &*temp);
+        *input = *temp;
+        count += 1;
+    }
+
+    Ok((output, count))
+}
+
+fn report(progress: &mut dyn ProgressHandler, current: EnScryptProgress) -> Result<()> {
+    match progress.on_progress(current) {
+        ProgressAction::Continue => Ok(()),
+        ProgressAction::Cancel => Err(SqrlError::with_kind(
+            SqrlErrorKind::Cancelled,
+            "EnScrypt was cancelled".to_owned(),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::progress::NoProgress;
+
+    fn test_config(iteration_factor: Option<u32>) -> ScryptConfig {
+        ScryptConfig {
+            random_salt: [7; 16],
+            log_n_factor: SCRYPT_DEFAULT_LOG_N,
+            iteration_factor,
         }
     }
 
-    Ok(output)
+    #[test]
+    fn en_scrypt_reports_iterations() {
+        let mut reported = Vec::new();
+        let output = en_scrypt(
+            b"password",
+            &test_config(Some(3)),
+            &mut |progress: EnScryptProgress| {
+                reported.push(progress);
+                ProgressAction::Continue
+            },
+        )
+        .unwrap();
+
+        assert_eq!(
+            reported,
+            (0..=3)
+                .map(|done| EnScryptProgress::Iterations { done, total: 3 })
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            output,
+            en_scrypt(b"password", &test_config(Some(3)), &mut NoProgress).unwrap()
+        );
+    }
+
+    #[test]
+    fn en_scrypt_can_be_cancelled() {
+        let mut config = test_config(None);
+        let result = mut_en_scrypt(b"password", &mut config, 5, &mut |_| ProgressAction::Cancel);
+
+        assert_eq!(result.unwrap_err().kind(), &SqrlErrorKind::Cancelled);
+        assert_eq!(config.iteration_factor, None);
+    }
 }
@@ -0,0 +1,77 @@
+//! A wrapper for secret data that is zeroed out when dropped, never printed by
+//! Debug and compared in constant time
+
+use std::{
+    fmt,
+    ops::{Deref, DerefMut},
+};
+use subtle::ConstantTimeEq;
+use zeroize::Zeroize;
+
+/// Secret data (such as a decrypted key or a rescue code) that is zeroed out
+/// when dropped
+///
+/// The contents are never printed by Debug, to keep secrets out of logs, and
+/// are compared in constant time.
+#[derive(Clone)]
+pub struct Secret<T: Zeroize>(T);
+
+impl<T: Zeroize> Secret<T> {
+    /// Take ownership of the secret data
+    pub fn new(value: T) -> Self {
+        Secret(value)
+    }
+}
+
+impl<T: Zeroize> Deref for Secret<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: Zeroize> DerefMut for Secret<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+impl<T: Zeroize> Drop for Secret<T> {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl<T: Zeroize> fmt::Debug for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[REDACTED]")
+    }
+}
+
+impl<T: Zeroize + AsRef<[u8]>> PartialEq for Secret<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_ref().ct_eq(other.0.as_ref()).into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn debug_is_redacted() {
+        let secret = Secret::new([42u8; 32]);
+        assert_eq!(format!("{:?}", secret), "[REDACTED]");
+    }
+
+    #[test]
+    fn compares_contents() {
+        assert_eq!(Secret::new([42u8; 32]), Secret::new([42u8; 32]));
+        assert_ne!(Secret::new([42u8; 32]), Secret::new([43u8; 32]));
+        assert_ne!(
+            Secret::new("1234".to_owned()),
+            Secret::new("12345".to_owned())
+        );
+    }
+}
@@ -0,0 +1,38 @@
+//! Warnings about a login that may not be safe to continue
+
+/// Something about a login that the user should be warned about
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SecurityWarning {
+    /// The login was started on this device, but the server saw the query
+    /// come from a different IP address than the one it gave the login page
+    /// to, so someone may be relaying the login (a man-in-the-middle)
+    PossibleMitm,
+}
+
+/// What a [`SecurityHandler`] wants to happen after a warning
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WarningAction {
+    /// Carry on with the login
+    Continue,
+    /// Stop before the ident is sent, failing the login with
+    /// [`SqrlErrorKind::Cancelled`](crate::error::SqrlErrorKind::Cancelled)
+    Abort,
+}
+
+/// Shows security warnings to the user, and decides whether the login continues
+///
+/// This is implemented for any `FnMut(SecurityWarning) -> WarningAction`
+/// closure.
+pub trait SecurityHandler {
+    /// Called when something about the login looks unsafe
+    fn on_warning(&mut self, warning: SecurityWarning) -> WarningAction;
+}
+
+impl<F> SecurityHandler for F
+where
+    F: FnMut(SecurityWarning) -> WarningAction,
+{
+    fn on_warning(&mut self, warning: SecurityWarning) -> WarningAction {
+        self(warning)
+    }
+}
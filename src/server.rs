@@ -0,0 +1,251 @@
+//! Verify the requests SQRL clients send to a server
+
+use crate::{error::SqrlError, Result};
+use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine};
+use ed25519_dalek::{Signature, VerifyingKey};
+use sqrl_protocol::client_request::ClientRequest;
+
+pub use crate::sqrl_server::{ServerAccount, SqrlServer};
+
+/// A request a SQRL client sent in the body of a POST
+pub struct ClientPost {
+    request: ClientRequest,
+    signed: String,
+}
+
+/// Whether the signatures on a [`ClientPost`] are valid
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Verification {
+    /// Every signature on the request is valid
+    Valid {
+        /// The request was also signed by a previous identity (pidk and pids)
+        previous_identity: bool,
+        /// The request was also signed by the identity's unlock request key (urs)
+        unlock_request: bool,
+    },
+    /// The request can't be trusted
+    Invalid(VerificationFailure),
+}
+
+/// Why a [`ClientPost`] failed verification
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VerificationFailure {
+    /// The ids signature does not match the idk
+    InvalidIdentitySignature,
+    /// The request has a pidk without a pids, or a pids without a pidk
+    MissingPreviousIdentitySignature,
+    /// The pids signature does not match the pidk
+    InvalidPreviousIdentitySignature,
+    /// The request has a urs, but the server has no vuk to check it against
+    MissingVerifyUnlockKey,
+    /// The urs signature does not match the vuk
+    InvalidUnlockRequestSignature,
+}
+
+impl ClientPost {
+    /// Parse the body of a client's POST
+    pub fn parse(body: &str) -> Result<Self> {
+        let request = ClientRequest::from_query_string(body.trim())?;
+
+        // The signatures cover the client and server values exactly as they were sent
+        let mut client = None;
+        let mut server = None;
+        for pair in body.trim().split('&') {
+            match pair.split_once('=') {
+                Some(("client", value)) => client = Some(value),
+                Some(("server", value)) => server = Some(value),
+                _ => (),
+            }
+        }
+        let (client, server) = client.zip(server).ok_or(SqrlError::new(
+            "The request is missing the client or server value".to_owned(),
+        ))?;
+
+        Ok(ClientPost {
+            request,
+            signed: format!("{}{}", client, server),
+        })
+    }
+
+    /// The parsed request
+    ///
+    /// None of the request can be trusted until it has been verified.
+    pub fn request(&self) -> &ClientRequest {
+        &self.request
+    }
+
+    /// Verify the signatures on the request
+    ///
+    /// The verify unlock key is the vuk the server stored when the identity
+    /// (or the previous identity, when replacing it) was associated. It is
+    /// only needed if the request has an unlock request signature.
+    pub fn verify(&self, verify_unlock_key: Option<&VerifyingKey>) -> Verification {
+        let params = &self.request.client_params;
+        if !self.check(&params.identity_key, &self.request.identity_signature) {
+            return Verification::Invalid(VerificationFailure::InvalidIdentitySignature);
+        }
+
+        let previous_identity = match (
+            params.previous_identity_key,
+            self.request.previous_identity_signature,
+        ) {
+            (Some(key), Some(signature)) => {
+                if !self.check(&key, &signature) {
+                    return Verification::Invalid(
+                        VerificationFailure::InvalidPreviousIdentitySignature,
+                    );
+                }
+                true
+            }
+            (None, None) => false,
+            _ => {
+                return Verification::Invalid(VerificationFailure::MissingPreviousIdentitySignature)
+            }
+        };
+
+        let unlock_request = match &self.request.unlock_request_signature {
+            Some(signature) => {
+                let key = match verify_unlock_key {
+                    Some(key) => key,
+                    None => {
+                        return Verification::Invalid(VerificationFailure::MissingVerifyUnlockKey)
+                    }
+                };
+                let signature = BASE64_URL_SAFE_NO_PAD
+                    .decode(signature)
+                    .ok()
+                    .and_then(|signature| Signature::from_slice(&signature).ok());
+                if !signature.is_some_and(|signature| self.check(key, &signature)) {
+                    return Verification::Invalid(
+                        VerificationFailure::InvalidUnlockRequestSignature,
+                    );
+                }
+                true
+            }
+            None => false,
+        };
+
+        Verification::Valid {
+            previous_identity,
+            unlock_request,
+        }
+    }
+
+    fn check(&self, key: &VerifyingKey, signature: &Signature) -> bool {
+        key.verify_strict(self.signed.as_bytes(), signature).is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        common::{encode_request, signed_string},
+        test_util::test_client,
+        SqrlClient,
+    };
+    use ed25519_dalek::Signer;
+    use sqrl_protocol::client_request::{ClientCommand, ClientParameters, ServerData};
+
+    const TEST_URL: &str = "sqrl://example.com/sqrl?nut=first";
+
+    fn signed_request(client: &SqrlClient) -> ClientRequest {
+        let identity = client.unlock("password").unwrap();
+        let params = ClientParameters::new(
+            ClientCommand::Query,
+            identity.get_public_identity(TEST_URL, None).unwrap(),
+        );
+        let server = ServerData::Url {
+            url: crate::parse_url(TEST_URL).unwrap(),
+        };
+        let mut request = ClientRequest::new(params, server, Signature::from_bytes(&[0; 64]));
+        identity
+            .sign_request(TEST_URL, None, &mut request, None)
+            .unwrap();
+        request
+    }
+
+    #[test]
+    fn verify_signed_request() {
+        let (mut client, rescue_code) = test_client();
+        let request = signed_request(&client);
+        let post = ClientPost::parse(&encode_request(&request)).unwrap();
+        assert_eq!(post.request().client_params, request.client_params);
+        assert_eq!(
+            post.verify(None),
+            Verification::Valid {
+                previous_identity: false,
+                unlock_request: false,
+            }
+        );
+
+        client.rekey_identity("password", &rescue_code).unwrap();
+        let post = ClientPost::parse(&encode_request(&signed_request(&client))).unwrap();
+        assert_eq!(
+            post.verify(None),
+            Verification::Valid {
+                previous_identity: true,
+                unlock_request: false,
+            }
+        );
+    }
+
+    #[test]
+    fn verify_rejects_bad_signatures() {
+        let (client, _) = test_client();
+        let mut request = signed_request(&client);
+        request.identity_signature = Signature::from_bytes(&[1; 64]);
+        let post = ClientPost::parse(&encode_request(&request)).unwrap();
+        assert_eq!(
+            post.verify(None),
+            Verification::Invalid(VerificationFailure::InvalidIdentitySignature)
+        );
+
+        let mut request = signed_request(&client);
+        request.previous_identity_signature = Some(Signature::from_bytes(&[1; 64]));
+        let post = ClientPost::parse(&encode_request(&request)).unwrap();
+        assert_eq!(
+            post.verify(None),
+            Verification::Invalid(VerificationFailure::MissingPreviousIdentitySignature)
+        );
+
+        assert!(ClientPost::parse("client=abc").is_err());
+    }
+
+    #[test]
+    fn verify_unlock_request_signature() {
+        let (client, rescue_code) = test_client();
+        let identity = client.unlock("password").unwrap();
+        let keys = identity
+            .generate_server_unlock_and_verify_unlock_keys()
+            .unwrap();
+        let unlock_key = client
+            .generate_unlock_request_signing_key(&rescue_code, keys.server_unlock_key.to_bytes())
+            .unwrap();
+
+        let mut request = signed_request(&client);
+        let signature = unlock_key.sign(signed_string(&request).as_bytes());
+        request.unlock_request_signature =
+            Some(BASE64_URL_SAFE_NO_PAD.encode(signature.to_bytes()));
+        let post = ClientPost::parse(&encode_request(&request)).unwrap();
+        assert_eq!(
+            post.verify(Some(&keys.verify_unlock_key)),
+            Verification::Valid {
+                previous_identity: false,
+                unlock_request: true,
+            }
+        );
+        assert_eq!(
+            post.verify(None),
+            Verification::Invalid(VerificationFailure::MissingVerifyUnlockKey)
+        );
+
+        let other_keys = identity
+            .generate_server_unlock_and_verify_unlock_keys()
+            .unwrap();
+        assert_eq!(
+            post.verify(Some(&other_keys.verify_unlock_key)),
+            Verification::Invalid(VerificationFailure::InvalidUnlockRequestSignature)
+        );
+    }
+}
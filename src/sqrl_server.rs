@@ -0,0 +1,531 @@
+//! A reference SQRL server that keeps its accounts in memory
+
+use crate::{
+    nut::{NutGenerator, NutValidation, NutValidator},
+    parse_url,
+    server::{ClientPost, Verification},
+    transport::SqrlTransport,
+    Result,
+};
+use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine};
+use ed25519_dalek::VerifyingKey;
+use rand::{rngs::OsRng, RngCore};
+use sqrl_protocol::{
+    client_request::{ClientCommand, ClientOption, ClientParameters, ServerData},
+    server_response::{ServerResponse, TIFValue},
+};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr},
+    time::{Duration, Instant},
+};
+use url::Url;
+
+// How long a nut can be used for after it was issued
+const NUT_MAX_AGE: Duration = Duration::from_secs(300);
+
+/// The keys a [`SqrlServer`] stores for an identity
+#[derive(Clone, Debug, PartialEq)]
+pub struct ServerAccount {
+    /// The server unlock key (suk) sent when the identity was associated
+    pub server_unlock_key: [u8; 32],
+    /// The verify unlock key (vuk) sent when the identity was associated
+    pub verify_unlock_key: VerifyingKey,
+    /// Whether SQRL logins have been disabled for the identity
+    pub disabled: bool,
+}
+
+/// A small SQRL server, for testing clients end to end
+///
+/// Accounts are stored in memory keyed by the identity key (idk). The server
+/// handles the query, ident, disable, enable and remove commands, and checks
+/// every nut and signature it receives. The server value of each request has
+/// to be the url or response the nut was issued in, so a client can't act on
+/// a response the server didn't send.
+#[derive(Debug)]
+pub struct SqrlServer {
+    host: String,
+    success_url: String,
+    ask: Option<String>,
+    nut_generator: NutGenerator,
+    nut_validator: NutValidator,
+    accounts: HashMap<[u8; 32], ServerAccount>,
+    // What each unused nut was issued in, and when
+    issued: HashMap<String, (Instant, Issued)>,
+}
+
+// The url or response a nut was sent to the client in, which the client has
+// to send back as the server value of its next request
+#[derive(Debug)]
+enum Issued {
+    LoginUrl(String),
+    Response(ServerResponse),
+}
+
+impl Issued {
+    fn matches(&self, server_data: &ServerData) -> bool {
+        match (self, server_data) {
+            (Issued::LoginUrl(issued), ServerData::Url { url }) => *issued == url.to_string(),
+            (
+                Issued::Response(issued),
+                ServerData::ServerResponse {
+                    server_response, ..
+                },
+            ) => issued == server_response,
+            _ => false,
+        }
+    }
+}
+
+impl SqrlServer {
+    /// Create a server for the host (such as "localhost:8443"), with a random nut key
+    pub fn new(host: &str) -> Self {
+        let mut nut_key = [0; 32];
+        OsRng.fill_bytes(&mut nut_key);
+
+        SqrlServer {
+            host: host.to_owned(),
+            success_url: format!("https://{}/", host),
+            ask: None,
+            nut_generator: NutGenerator::new(nut_key),
+            nut_validator: NutValidator::new(nut_key, NUT_MAX_AGE),
+            accounts: HashMap::new(),
+            issued: HashMap::new(),
+        }
+    }
+
+    /// Set the url the browser is sent to after a successful login
+    pub fn set_success_url(&mut self, success_url: &str) {
+        self.success_url = success_url.to_owned();
+    }
+
+    /// Set the ask= value to send with every query response
+    pub fn set_ask(&mut self, ask: Option<String>) {
+        self.ask = ask;
+    }
+
+    /// Generate a sqrl:// url with a new nut for a client's login page
+    pub fn login_url(&mut self, client_ip: &IpAddr) -> String {
+        let nut = self.nut_generator.generate(client_ip);
+        let url = format!("sqrl://{}/sqrl?nut={}", self.host, nut);
+        if let Ok(sqrl_url) = parse_url(&url) {
+            self.issue(nut, Issued::LoginUrl(sqrl_url.to_string()));
+        }
+        url
+    }
+
+    /// Look up the account for an identity key
+    pub fn account(&self, identity_key: &VerifyingKey) -> Option<&ServerAccount> {
+        self.accounts.get(identity_key.as_bytes())
+    }
+
+    /// Add an account, such as one associated before the server started
+    pub fn add_account(&mut self, identity_key: &VerifyingKey, account: ServerAccount) {
+        self.accounts.insert(identity_key.to_bytes(), account);
+    }
+
+    /// Handle a client's POST to the url, returning the response to send back
+    pub fn handle_request(&mut self, url: &str, body: &str, client_ip: &IpAddr) -> ServerResponse {
+        let response = self.answer(url, body, client_ip);
+
+        // Keep the response as the client will parse it, so the two compare equal
+        if let Ok(issued) = ServerResponse::from_base64(&response.to_base64()) {
+            self.issue(response.nut.clone(), Issued::Response(issued));
+        }
+        response
+    }
+
+    fn answer(&mut self, url: &str, body: &str, client_ip: &IpAddr) -> ServerResponse {
+        let nut = Url::parse(url).ok().and_then(|url| {
+            url.query_pairs()
+                .find(|(key, _)| key == "nut")
+                .map(|(_, value)| value.into_owned())
+        });
+        let issued = nut.as_ref().and_then(|nut| self.issued.remove(nut));
+        let ips_match = match nut.map(|nut| self.nut_validator.validate(&nut, client_ip)) {
+            Some(NutValidation::Valid { ips_match }) => ips_match,
+            // The client can try again with the new nut in the response
+            _ => {
+                return self.respond(
+                    client_ip,
+                    vec![TIFValue::CommandFailed, TIFValue::TransientError],
+                )
+            }
+        };
+
+        // The client has to send back exactly what the nut was issued in
+        let post = match ClientPost::parse(body) {
+            Ok(post)
+                if issued
+                    .is_some_and(|(_, issued)| issued.matches(&post.request().server_data)) =>
+            {
+                post
+            }
+            _ => {
+                return self.respond(
+                    client_ip,
+                    vec![TIFValue::CommandFailed, TIFValue::ClientFailure],
+                )
+            }
+        };
+        let params = &post.request().client_params;
+        let current = self.accounts.get(params.identity_key.as_bytes()).cloned();
+        let previous = match &current {
+            Some(_) => None,
+            None => params
+                .previous_identity_key
+                .and_then(|key| self.accounts.get(key.as_bytes()).cloned()),
+        };
+        let matched = current.as_ref().or(previous.as_ref());
+
+        // The urs is checked against the vuk of whichever identity matched
+        let unlock_request = match post.verify(matched.map(|account| &account.verify_unlock_key)) {
+            Verification::Valid { unlock_request, .. } => unlock_request,
+            Verification::Invalid(_) => {
+                return self.respond(
+                    client_ip,
+                    vec![TIFValue::CommandFailed, TIFValue::ClientFailure],
+                )
+            }
+        };
+
+        let mut flags = Vec::new();
+        if ips_match {
+            flags.push(TIFValue::IpsMatch);
+        }
+        if current.is_some() {
+            flags.push(TIFValue::CurrentIdMatch);
+        } else if previous.is_some() {
+            flags.push(TIFValue::PreviousIdMatch);
+        }
+
+        let mut logged_in = false;
+        match params.command {
+            ClientCommand::Query => (),
+            ClientCommand::Ident => {
+                if let Some(account) = &current {
+                    if account.disabled {
+                        flags.push(TIFValue::CommandFailed);
+                    } else {
+                        logged_in = true;
+                    }
+                } else if previous.is_some() && !unlock_request {
+                    // Only the owner of the previous identity can replace it
+                    flags.push(TIFValue::CommandFailed);
+                    flags.push(TIFValue::ClientFailure);
+                } else if let Some((server_unlock_key, verify_unlock_key)) =
+                    decode_unlock_keys(params)
+                {
+                    if let (Some(previous_key), Some(_)) = (params.previous_identity_key, &previous)
+                    {
+                        self.accounts.remove(previous_key.as_bytes());
+                    }
+                    self.accounts.insert(
+                        params.identity_key.to_bytes(),
+                        ServerAccount {
+                            server_unlock_key,
+                            verify_unlock_key,
+                            disabled: false,
+                        },
+                    );
+                    logged_in = true;
+                } else {
+                    // A new identity can't be associated without the keys to unlock it later
+                    flags.push(TIFValue::CommandFailed);
+                    flags.push(TIFValue::ClientFailure);
+                }
+            }
+            ClientCommand::Disable => match self.accounts.get_mut(params.identity_key.as_bytes()) {
+                Some(account) => account.disabled = true,
+                None => flags.push(TIFValue::CommandFailed),
+            },
+            ClientCommand::Enable | ClientCommand::Remove => {
+                if current.is_none() {
+                    flags.push(TIFValue::CommandFailed);
+                } else if !unlock_request {
+                    // These commands need to be signed by the identity's unlock request key
+                    flags.push(TIFValue::CommandFailed);
+                    flags.push(TIFValue::ClientFailure);
+                } else if params.command == ClientCommand::Enable {
+                    if let Some(account) = self.accounts.get_mut(params.identity_key.as_bytes()) {
+                        account.disabled = false;
+                    }
+                    logged_in = true;
+                } else {
+                    self.accounts.remove(params.identity_key.as_bytes());
+                }
+            }
+        }
+
+        // Report whether the identity is disabled once the command has run
+        let account = self
+            .accounts
+            .get(params.identity_key.as_bytes())
+            .or(previous.as_ref());
+        if account.is_some_and(|account| account.disabled) {
+            flags.push(TIFValue::SqrlDisabled);
+        }
+
+        // The client needs the suk to sign unlock requests for a previous or disabled identity
+        let suk_requested = params
+            .options
+            .as_ref()
+            .is_some_and(|options| options.contains(&ClientOption::ServerUnlockKey));
+        let server_unlock_key = match account {
+            Some(account) if suk_requested || account.disabled || previous.is_some() => {
+                Some(BASE64_URL_SAFE_NO_PAD.encode(account.server_unlock_key))
+            }
+            _ => None,
+        };
+
+        let mut response = self.respond(client_ip, flags);
+        response.server_unlock_key = server_unlock_key;
+        if params.command == ClientCommand::Query {
+            response.ask = self.ask.clone();
+        }
+        if logged_in {
+            response.success_url = Some(self.success_url.clone());
+        }
+        response
+    }
+
+    // Remember the server value a nut was issued in, forgetting any nuts that
+    // are too old to be used
+    fn issue(&mut self, nut: String, issued: Issued) {
+        let now = Instant::now();
+        self.issued
+            .retain(|_, (time, _)| now.duration_since(*time) <= NUT_MAX_AGE);
+        self.issued.insert(nut, (now, issued));
+    }
+
+    fn respond(&mut self, client_ip: &IpAddr, flags: Vec<TIFValue>) -> ServerResponse {
+        let nut = self.nut_generator.generate(client_ip);
+        let query_url = format!("/sqrl?nut={}", nut);
+        ServerResponse::new(nut, flags, query_url)
+    }
+}
+
+// The suk and vuk are sent base64url encoded, and stored as keys
+fn decode_unlock_keys(params: &ClientParameters) -> Option<([u8; 32], VerifyingKey)> {
+    let server_unlock_key = BASE64_URL_SAFE_NO_PAD
+        .decode(params.server_unlock_key.as_ref()?)
+        .ok()?;
+    let verify_unlock_key = BASE64_URL_SAFE_NO_PAD
+        .decode(params.verify_unlock_key.as_ref()?)
+        .ok()?;
+    Some((
+        server_unlock_key.try_into().ok()?,
+        VerifyingKey::from_bytes(&verify_unlock_key.try_into().ok()?).ok()?,
+    ))
+}
+
+// Requests sent through the transport come from the same machine
+impl SqrlTransport for SqrlServer {
+    fn post(&mut self, url: &str, body: &str) -> Result<String> {
+        let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        Ok(self.handle_request(url, body, &localhost).to_base64())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        common::{encode_request, signed_string},
+        login::{LoginFailure, LoginOutcome, LoginSession},
+        parse_url,
+        test_util::test_client,
+        UnlockedIdentity,
+    };
+    use ed25519_dalek::{Signature, Signer};
+    use sqrl_protocol::client_request::{ClientParameters, ClientRequest};
+
+    const TEST_HOST: &str = "localhost:8443";
+
+    fn localhost() -> IpAddr {
+        IpAddr::V4(Ipv4Addr::LOCALHOST)
+    }
+
+    fn login(server: &mut SqrlServer, identity: &UnlockedIdentity) -> LoginOutcome {
+        let url = server.login_url(&localhost());
+        LoginSession::new(identity, &url, None)
+            .unwrap()
+            .run(server)
+            .unwrap()
+    }
+
+    // Send a query followed by another command, returning the server's response to the command
+    fn send_command(
+        server: &mut SqrlServer,
+        identity: &UnlockedIdentity,
+        command: ClientCommand,
+        unlock_identity: Option<&UnlockedIdentity>,
+    ) -> ServerResponse {
+        let url = server.login_url(&localhost());
+        let identity_key = identity.get_public_identity(&url, None).unwrap();
+        let mut params = ClientParameters::new(ClientCommand::Query, identity_key);
+        if unlock_identity.is_some() {
+            params.options = Some(vec![ClientOption::ServerUnlockKey]);
+        }
+        let mut query = ClientRequest::new(
+            params,
+            ServerData::Url {
+                url: parse_url(&url).unwrap(),
+            },
+            Signature::from_bytes(&[0; 64]),
+        );
+        identity.sign_request(&url, None, &mut query, None).unwrap();
+        let post_url = url.replacen("sqrl://", "https://", 1);
+        let response = server.handle_request(&post_url, &encode_request(&query), &localhost());
+
+        let post_url = format!("https://{}{}", TEST_HOST, response.query_url);
+        let server_unlock_key = response.server_unlock_key.clone();
+        let mut request = ClientRequest::new(
+            ClientParameters::new(command, identity_key),
+            ServerData::from_base64(&response.to_base64()).unwrap(),
+            Signature::from_bytes(&[0; 64]),
+        );
+        identity
+            .sign_request(&url, None, &mut request, None)
+            .unwrap();
+        if let Some(unlock_identity) = unlock_identity {
+            let server_unlock_key = BASE64_URL_SAFE_NO_PAD
+                .decode(server_unlock_key.unwrap())
+                .unwrap();
+            let unlock_key = unlock_identity
+                .generate_unlock_request_signing_key(server_unlock_key.try_into().unwrap(), None)
+                .unwrap();
+            let signature = unlock_key.sign(signed_string(&request).as_bytes());
+            request.unlock_request_signature =
+                Some(BASE64_URL_SAFE_NO_PAD.encode(signature.to_bytes()));
+        }
+        server.handle_request(&post_url, &encode_request(&request), &localhost())
+    }
+
+    #[test]
+    fn associate_and_log_in() {
+        let (mut client, rescue_code) = test_client();
+        let identity = client.unlock("password").unwrap();
+        let mut server = SqrlServer::new(TEST_HOST);
+
+        assert!(matches!(
+            login(&mut server, &identity),
+            LoginOutcome::IdentityAssociated { .. }
+        ));
+        assert_eq!(
+            login(&mut server, &identity),
+            LoginOutcome::LoggedIn {
+                success_url: Some("https://localhost:8443/".to_owned())
+            }
+        );
+
+        client.rekey_identity("password", &rescue_code).unwrap();
+        let identity = client.unlock("password").unwrap();
+        assert!(matches!(
+            login(&mut server, &identity),
+            LoginOutcome::PreviousIdentityUpdated { .. }
+        ));
+        assert!(matches!(
+            login(&mut server, &identity),
+            LoginOutcome::LoggedIn { .. }
+        ));
+    }
+
+    #[test]
+    fn disable_enable_and_remove() {
+        let (client, rescue_code) = test_client();
+        let identity = client.unlock("password").unwrap();
+        let rescued = client.unlock_with_rescue_code(&rescue_code).unwrap();
+        let mut server = SqrlServer::new(TEST_HOST);
+        login(&mut server, &identity);
+
+        let response = send_command(&mut server, &identity, ClientCommand::Disable, None);
+        assert!(response
+            .transaction_indication_flags
+            .contains(&TIFValue::SqrlDisabled));
+        assert_eq!(
+            login(&mut server, &identity),
+            LoginOutcome::Failed(LoginFailure::SqrlDisabled)
+        );
+
+        // Enabling without the unlock request signature fails
+        let response = send_command(&mut server, &identity, ClientCommand::Enable, None);
+        assert!(response
+            .transaction_indication_flags
+            .contains(&TIFValue::CommandFailed));
+        assert!(matches!(
+            login(&mut server, &rescued),
+            LoginOutcome::IdentityEnabled { .. }
+        ));
+
+        let response = send_command(
+            &mut server,
+            &identity,
+            ClientCommand::Remove,
+            Some(&rescued),
+        );
+        assert!(!response
+            .transaction_indication_flags
+            .contains(&TIFValue::CommandFailed));
+        let url = server.login_url(&localhost());
+        assert!(server
+            .account(&identity.get_public_identity(&url, None).unwrap())
+            .is_none());
+    }
+
+    #[test]
+    fn server_value_must_be_the_issued_response() {
+        let (client, _) = test_client();
+        let identity = client.unlock("password").unwrap();
+        let mut server = SqrlServer::new(TEST_HOST);
+
+        let url = server.login_url(&localhost());
+        let mut session = LoginSession::new(&identity, &url, None).unwrap();
+        let query = session.next_request().unwrap();
+        let response =
+            server.handle_request(session.post_url(), &encode_request(&query), &localhost());
+
+        // Echo a response that claims the identity is already known
+        let mut forged = ServerResponse::from_base64(&response.to_base64()).unwrap();
+        forged
+            .transaction_indication_flags
+            .push(TIFValue::CurrentIdMatch);
+        let identity_key = identity.get_public_identity(&url, None).unwrap();
+        let mut ident = ClientRequest::new(
+            ClientParameters::new(ClientCommand::Ident, identity_key),
+            ServerData::from_base64(&forged.to_base64()).unwrap(),
+            Signature::from_bytes(&[0; 64]),
+        );
+        identity.sign_request(&url, None, &mut ident, None).unwrap();
+        let post_url = format!("https://{}{}", TEST_HOST, response.query_url);
+        let response = server.handle_request(&post_url, &encode_request(&ident), &localhost());
+
+        assert!(response
+            .transaction_indication_flags
+            .contains(&TIFValue::ClientFailure));
+        assert!(server.account(&identity_key).is_none());
+    }
+
+    #[test]
+    fn nut_from_another_ip_is_reported() {
+        let (client, _) = test_client();
+        let identity = client.unlock("password").unwrap();
+        let mut server = SqrlServer::new(TEST_HOST);
+
+        let url = server.login_url(&IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)));
+        let mut session = LoginSession::new(&identity, &url, None).unwrap();
+        let query = session.next_request().unwrap();
+        let response =
+            server.handle_request(session.post_url(), &encode_request(&query), &localhost());
+        assert!(!response
+            .transaction_indication_flags
+            .contains(&TIFValue::IpsMatch));
+
+        // The same nut can't be used twice
+        let response =
+            server.handle_request(session.post_url(), &encode_request(&query), &localhost());
+        assert!(response
+            .transaction_indication_flags
+            .contains(&TIFValue::TransientError));
+    }
+}
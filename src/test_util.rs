@@ -0,0 +1,20 @@
+//! Fixtures shared by the tests
+
+use crate::{OperationOptions, ScryptPolicy, Secret, SqrlClient, UnlockedIdentity};
+
+/// Create an identity protected by "password"
+///
+/// It uses a single EnScrypt iteration, so the tests don't spend their time
+/// deriving keys.
+pub(crate) fn test_client() -> (SqrlClient, Secret<String>) {
+    let options = OperationOptions {
+        policy: Some(ScryptPolicy::with_iterations(1)),
+        ..Default::default()
+    };
+    SqrlClient::new_with_options("password", options).unwrap()
+}
+
+/// Create an identity with [`test_client`] and unlock it
+pub(crate) fn test_identity() -> UnlockedIdentity {
+    test_client().0.unlock("password").unwrap()
+}
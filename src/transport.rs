@@ -0,0 +1,15 @@
+//! Sending SQRL requests to a server, and an in-memory server for testing
+//!
+//! `MockServer` is only available with the `server` feature. Enable it for
+//! your dev-dependencies to use it in your own tests.
+
+use crate::Result;
+
+#[cfg(any(test, feature = "server"))]
+pub use crate::mock_server::MockServer;
+
+/// A way of sending SQRL requests to a server
+pub trait SqrlTransport {
+    /// POST the body to the https url, returning the body of the server's response
+    fn post(&mut self, url: &str, body: &str) -> Result<String>;
+}
@@ -0,0 +1,53 @@
+use crate::{error::SqrlError, readable_vector::ReadableVector, Result};
+use byteorder::{LittleEndian, WriteBytesExt};
+use std::{collections::VecDeque, io::Write};
+
+/// A block of S4 data whose type this library does not understand
+///
+/// Other SQRL clients may write additional block types. These are kept as
+/// opaque data so that they can be written back unchanged, and in the same
+/// place relative to the other blocks.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct UnknownBlock {
+    block_type: u16,
+    data: Vec<u8>,
+    position: usize,
+}
+
+impl UnknownBlock {
+    pub(crate) fn from_binary(
+        block_type: u16,
+        block_length: u16,
+        position: usize,
+        binary: &mut VecDeque<u8>,
+    ) -> Result<Self> {
+        // The block length includes the length and type we've already read
+        if block_length < 4 {
+            return Err(SqrlError::new("Invalid binary data".to_owned()));
+        }
+
+        Ok(UnknownBlock {
+            block_type,
+            data: binary.next_sub_array((block_length - 4).into())?,
+            position,
+        })
+    }
+
+    /// The index of the block among all the blocks it was read with
+    pub(crate) fn position(&self) -> usize {
+        self.position
+    }
+
+    pub(crate) fn to_binary(&self, output: &mut Vec<u8>) -> Result<()> {
+        output.write_u16::<LittleEndian>(self.len())?;
+        output.write_u16::<LittleEndian>(self.block_type)?;
+        output.write_all(&self.data)?;
+
+        Ok(())
+    }
+
+    fn len(&self) -> u16 {
+        // This can't overflow, as the data was read using a u16 length
+        self.data.len() as u16 + 4
+    }
+}
@@ -0,0 +1,291 @@
+//! An unlocked SQRL identity that can be used without re-entering the password
+
+use crate::{
+    common::{en_hash, signed_string, unlock_request_signing_key, IdentityUnlockKeys},
+    error::{SqrlError, SqrlErrorKind},
+    identity_information::EncryptedKeyPair,
+    parse_url,
+    secret::Secret,
+    ConfigOptions, GetKey, IdentityKey, Result,
+};
+use aes_gcm::aead::OsRng;
+use base64::{prelude::BASE64_URL_SAFE, Engine};
+use ed25519_dalek::{Signer, SigningKey, VerifyingKey};
+use hmac::{Hmac, Mac};
+use rand::{CryptoRng, RngCore};
+use sha2::Sha256;
+use sqrl_protocol::client_request::{ClientOption, ClientRequest, ServerData};
+use std::collections::VecDeque;
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+/// A SQRL identity that has been decrypted using the user's password
+///
+/// Decrypting the identity requires running EnScrypt, which is slow by design.
+/// An UnlockedIdentity holds on to the decrypted keys so that multiple
+/// operations (such as signing the query and then the ident of a single login)
+/// only pay that cost once. The decrypted keys are zeroed out when the
+/// UnlockedIdentity is dropped.
+#[derive(Clone, Debug)]
+pub struct UnlockedIdentity {
+    identity_master_key: Secret<IdentityKey>,
+    identity_lock_key: Secret<IdentityKey>,
+    previous_identity_unlock_keys: VecDeque<Secret<IdentityKey>>,
+    identity_unlock_key: Option<Secret<IdentityKey>>,
+    option_flags: Vec<ConfigOptions>,
+}
+
+impl UnlockedIdentity {
+    pub(crate) fn new(
+        identity_master_key: Secret<IdentityKey>,
+        identity_lock_key: Secret<IdentityKey>,
+        previous_identity_unlock_keys: VecDeque<Secret<IdentityKey>>,
+        option_flags: Vec<ConfigOptions>,
+    ) -> Self {
+        UnlockedIdentity {
+            identity_master_key,
+            identity_lock_key,
+            previous_identity_unlock_keys,
+            identity_unlock_key: None,
+            option_flags,
+        }
+    }
+
+    // Only identities unlocked with the rescue code can sign unlock requests for the current identity
+    pub(crate) fn with_identity_unlock_key(
+        mut self,
+        identity_unlock_key: Secret<IdentityKey>,
+    ) -> Self {
+        self.identity_unlock_key = Some(identity_unlock_key);
+        self
+    }
+
+    pub(crate) fn has_identity_unlock_key(&self) -> bool {
+        self.identity_unlock_key.is_some()
+    }
+
+    /// Sign a client request with the key generated by the url and alternate identity
+    ///
+    /// If the request echoes a server response that asks for a secret index
+    /// (sin=), the ins (and pins for the previous identity) are filled in too.
+    ///
+    /// A request without options gets the opt= values from the user's stored
+    /// settings (see [`UnlockedIdentity::client_options`]). Setting the options
+    /// beforehand, even to an empty list, overrides them for this request.
+    ///
+    /// The suk and vuk are not added. An ident that associates a new identity
+    /// with a site needs them, so set them on the client parameters from
+    /// [`UnlockedIdentity::generate_server_unlock_and_verify_unlock_keys`]
+    /// before signing. [`crate::login::LoginSession`] does this itself.
+    ///
+    /// The signatures are made over the encoding from
+    /// [`crate::common::encode_request`], so send the request with that.
+    pub fn sign_request(
+        &self,
+        url: &str,
+        alternate_identity: Option<&str>,
+        request: &mut ClientRequest,
+        previous_key_index: Option<usize>,
+    ) -> Result<()> {
+        self.sign_request_with_previous(
+            url,
+            alternate_identity,
+            request,
+            Some(previous_key_index.unwrap_or(0)),
+        )
+    }
+
+    // Sign the request, only adding a previous identity (pidk and pids) if
+    // there is one at the index
+    pub(crate) fn sign_request_with_previous(
+        &self,
+        url: &str,
+        alternate_identity: Option<&str>,
+        request: &mut ClientRequest,
+        previous_key_index: Option<usize>,
+    ) -> Result<()> {
+        let auth_domain = parse_url(url)?.get_auth_domain();
+        let private_key = self
+            .identity_master_key
+            .get_private_key(&auth_domain, alternate_identity)?;
+
+        request.client_params.identity_key = private_key.verifying_key();
+
+        let options = request
+            .client_params
+            .options
+            .take()
+            .unwrap_or_else(|| self.client_options());
+        request.client_params.options = if options.is_empty() {
+            None
+        } else {
+            Some(options)
+        };
+
+        let secret_index = match &request.server_data {
+            ServerData::ServerResponse {
+                server_response, ..
+            } => server_response.secret_index.clone(),
+            ServerData::Url { .. } => None,
+        };
+        if let Some(secret_index) = &secret_index {
+            request.client_params.index_secret =
+                Some(secret_index_key(&private_key, secret_index)?);
+        }
+
+        let previous_key =
+            previous_key_index.and_then(|index| self.previous_identity_unlock_keys.get(index));
+        if let Some(previous_key) = previous_key {
+            let previous_identity_master_key = Secret::new(en_hash(&**previous_key));
+            let previous_private_key =
+                previous_identity_master_key.get_private_key(&auth_domain, alternate_identity)?;
+            request.client_params.previous_identity_key =
+                Some(previous_private_key.verifying_key());
+            if let Some(secret_index) = &secret_index {
+                request.client_params.previous_index_secret =
+                    Some(secret_index_key(&previous_private_key, secret_index)?);
+            }
+            request.previous_identity_signature =
+                Some(previous_private_key.sign(signed_string(request).as_bytes()));
+        }
+
+        // Sign last, as we need to set the current and previous key ids
+        request.identity_signature = private_key.sign(signed_string(request).as_bytes());
+
+        Ok(())
+    }
+
+    /// Get a sin value based on the url and alternate identity
+    pub fn get_secret_index_key(
+        &self,
+        url: &str,
+        alternate_identity: Option<&str>,
+        secret_index: &str,
+    ) -> Result<String> {
+        let private_key = self.get_private_key(url, alternate_identity)?;
+        secret_index_key(&private_key, secret_index)
+    }
+
+    /// Retrieve the verifying key for a sqrl url
+    pub fn get_public_identity(
+        &self,
+        url: &str,
+        alternate_identity: Option<&str>,
+    ) -> Result<VerifyingKey> {
+        Ok(self
+            .get_private_key(url, alternate_identity)?
+            .verifying_key())
+    }
+
+    /// The configuration options stored with the identity
+    pub fn option_flags(&self) -> &[ConfigOptions] {
+        &self.option_flags
+    }
+
+    /// The opt= values that the user's stored settings ask for
+    ///
+    /// [`ConfigOptions::SqrlOnlyLogin`] adds sqrlonly, and
+    /// [`ConfigOptions::NoSqrlBypass`] adds hardlock.
+    pub fn client_options(&self) -> Vec<ClientOption> {
+        let mut options = Vec::new();
+        if self.option_flags.contains(&ConfigOptions::SqrlOnlyLogin) {
+            options.push(ClientOption::SQRLOnly);
+        }
+        if self.option_flags.contains(&ConfigOptions::NoSqrlBypass) {
+            options.push(ClientOption::Hardlock);
+        }
+        options
+    }
+
+    /// The number of previous identities (from before the identity was rekeyed)
+    /// that are kept, the most recent first
+    pub fn previous_identity_count(&self) -> usize {
+        self.previous_identity_unlock_keys.len()
+    }
+
+    /// Generate the server unlock and verify unlock keys needed for unlocking
+    /// an identity with a server
+    ///
+    /// A new random pair is generated on every call, so no two sites share
+    /// the same keys. [`crate::login::LoginSession`] calls this itself when it
+    /// associates the identity with a site.
+    pub fn generate_server_unlock_and_verify_unlock_keys(&self) -> Result<IdentityUnlockKeys> {
+        self.generate_server_unlock_and_verify_unlock_keys_with_rng(&mut OsRng)
+    }
+
+    /// Generate the server unlock and verify unlock keys needed for unlocking
+    /// an identity with a server, using the provided random number generator
+    pub fn generate_server_unlock_and_verify_unlock_keys_with_rng<R: CryptoRng + RngCore>(
+        &self,
+        rng: &mut R,
+    ) -> Result<IdentityUnlockKeys> {
+        let identity_lock_key = PublicKey::from(*self.identity_lock_key);
+
+        // Generate the random secret key and the server unlock key (the matching public key)
+        let random_key = EphemeralSecret::random_from_rng(rng);
+        let server_unlock_key = PublicKey::from(&random_key);
+
+        // Diffie-Hellman the random key with the identity lock key
+        // Take that shared secret key and use it to generate an ed25519 key pair
+        let shared_secret = random_key.diffie_hellman(&identity_lock_key);
+        let secret_key = SigningKey::from_bytes(shared_secret.as_bytes());
+        let verify_unlock_key = VerifyingKey::from(&secret_key);
+
+        Ok(IdentityUnlockKeys::new(
+            server_unlock_key,
+            verify_unlock_key,
+        ))
+    }
+
+    /// Generate the key that signs unlock requests (urs), using the server unlock
+    /// key the server has stored for the identity
+    ///
+    /// With a previous key index, the key is for that previous identity.
+    /// Otherwise it is for the current identity, which is only possible if the
+    /// identity was unlocked using the rescue code.
+    pub fn generate_unlock_request_signing_key(
+        &self,
+        server_unlock_key: [u8; 32],
+        previous_key_index: Option<usize>,
+    ) -> Result<SigningKey> {
+        let identity_unlock_key = match previous_key_index {
+            Some(index) => self
+                .previous_identity_unlock_keys
+                .get(index)
+                .ok_or(SqrlError::new(format!(
+                    "There is no previous identity {}",
+                    index
+                )))?,
+            None => self
+                .identity_unlock_key
+                .as_ref()
+                .ok_or(SqrlError::with_kind(
+                    SqrlErrorKind::RescueCodeRequired,
+                    "The identity needs to be unlocked with the rescue code".to_owned(),
+                ))?,
+        };
+
+        Ok(unlock_request_signing_key(
+            identity_unlock_key,
+            server_unlock_key,
+        ))
+    }
+
+    pub(crate) fn keys(&self) -> EncryptedKeyPair {
+        EncryptedKeyPair {
+            identity_master_key: self.identity_master_key.clone(),
+            identity_lock_key: self.identity_lock_key.clone(),
+        }
+    }
+
+    fn get_private_key(&self, url: &str, alternate_identity: Option<&str>) -> Result<SigningKey> {
+        self.identity_master_key
+            .get_private_key(&parse_url(url)?.get_auth_domain(), alternate_identity)
+    }
+}
+
+fn secret_index_key(private_key: &SigningKey, secret_index: &str) -> Result<String> {
+    let hash = Secret::new(en_hash(&*Secret::new(private_key.to_bytes())));
+    let mut hmac = Hmac::<Sha256>::new_from_slice(hash.as_slice())?;
+    hmac.update(secret_index.as_bytes());
+    Ok(BASE64_URL_SAFE.encode(hmac.finalize().into_bytes()))
+}
@@ -0,0 +1,78 @@
+//! Run the sqrl binary, passing the passwords on stdin
+
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command, Output, Stdio},
+};
+
+fn sqrl(identity: &Path, args: &[&str], stdin: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_sqrl"))
+        .arg("--identity")
+        .arg(identity)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(stdin.as_bytes())
+        .unwrap();
+
+    let output = child.wait_with_output().unwrap();
+    assert!(
+        output.status.success(),
+        "sqrl {:?} failed: {}",
+        args,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    output
+}
+
+fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("sqrl-cli-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn create_export_import_round_trip() {
+    let dir = test_dir("round-trip");
+    let identity = dir.join("identity.sqrl");
+    let export = dir.join("identity.txt");
+    let imported = dir.join("imported.sqrl");
+
+    let created = sqrl(&identity, &["create"], "password\n");
+    assert!(String::from_utf8_lossy(&created.stdout).contains("Rescue code"));
+
+    sqrl(
+        &identity,
+        &[
+            "export",
+            "--format",
+            "base64",
+            "--output",
+            export.to_str().unwrap(),
+        ],
+        "",
+    );
+    assert!(fs::read_to_string(&export).unwrap().starts_with("SQRLDATA"));
+
+    sqrl(
+        &imported,
+        &["import", "--format", "base64", export.to_str().unwrap()],
+        "",
+    );
+    assert_eq!(fs::read(&identity).unwrap(), fs::read(&imported).unwrap());
+
+    let verified = sqrl(&imported, &["verify"], "password\n");
+    assert!(String::from_utf8_lossy(&verified.stdout).contains("The password is correct"));
+
+    let _ = fs::remove_dir_all(&dir);
+}